@@ -38,10 +38,11 @@ cd $WORKBENCH
 curl -O $UPDATE_URL/update.json
 if [ -f "update.json" ]
 then
+	# Saves the build, differy package skips published revs as well.
 	export LATEST=$(jq -r -c '.latest' update.json)
-	if [ $LATEST == $REV]
+	if [ "$LATEST" == "$REV" ]
 	then
-		echo "Bundle already exsits for $REV"
+		echo "Bundle already exists for $REV"
 		exit 0
 	fi
 fi
@@ -69,24 +70,10 @@ done
 cd $WORKBENCH/interactive-examples
 npm install
 npm run build
-mv docs $BUILD_OUT_ROOT/examples
 
 cd $WORKBENCH
-if [ -f "update.json" ]
-then
-	for OLD_REV in $(jq -r -c '.updates[]' update.json)
-	do
-		curl -O $UPDATE_URL/packages/$OLD_REV-checksums.zip
-	done
-	curl -O $UPDATE_URL/packages/$LATEST-checksums.zip
-fi
-
-differy package $BUILD_OUT_ROOT --rev $REV
-cp update.json ${REV}-update.json
-cp ${REV}-content.json content.json
+differy package $BUILD_OUT_ROOT --root $WORKBENCH/interactive-examples/docs:examples --rev $REV --content $CONTENT_ROOT --checksums-dir $UPDATE_URL/packages
 
-# Sync to GCP
-gsutil -m -h "Cache-Control:public, max-age=86400" cp "${REV}-*.zip" gs://${GCS_BUCKET}/packages/
-gsutil -m -h "Cache-Control:public, max-age=86400" cp "${REV}-*.json" gs://${GCS_BUCKET}/packages/
-gsutil cp update.json gs://${GCS_BUCKET}/
-gsutil cp content.json gs://${GCS_BUCKET}/
+# Sync to GCP via its S3 compatible API, AWS_ACCESS_KEY_ID and
+# AWS_SECRET_ACCESS_KEY hold an HMAC key of the bucket.
+differy publish --rev $REV --to s3://${GCS_BUCKET} --s3-endpoint https://storage.googleapis.com --s3-region auto
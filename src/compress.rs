@@ -1,11 +1,19 @@
 use async_std::fs::{read, read_to_string};
-use async_std::path::Path;
+use async_std::path::{Path, PathBuf};
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
 use std::io::{Read, Write};
-use walkdir::WalkDir;
+use zip::read::ZipFile;
 use zip::result::ZipResult;
 use zip::write::SimpleFileOptions;
 use zip::{CompressionMethod, ZipArchive, ZipWriter};
 
+use crate::exclude::Exclude;
+use crate::tree::Tree;
+
 const APP_REPLACEMENTS: &[(&str, &str)] = &[
     (
         "src=\\\"https://interactive-examples.mdn.mozilla.net",
@@ -25,6 +33,111 @@ const WEB_REPLACEMENTS: &[(&str, &str)] = &[
     ("src=\\\"https://live-samples.mdn.mozilla.net", "src=\\\""),
 ];
 
+/// Name of the manifest entry of content and update zips. It lives in a
+/// directory of its own, so it can't clash with a `manifest.json` of the
+/// build.
+pub(crate) const ZIP_MANIFEST_FILENAME: &str = ".differy/manifest.json";
+/// Bumped when the manifest changes incompatibly. Version 1 manifests were
+/// only written into the last part of a split zip and listed the files of
+/// all parts, since version 2 every part lists exactly its own files.
+pub(crate) const ZIP_MANIFEST_VERSION: u32 = 2;
+
+/// Which part of a split zip a manifest describes.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ZipPart {
+    /// Starting at 1, as in the part's file name.
+    pub index: usize,
+    pub count: usize,
+}
+
+/// What a content or update zip contains, written as the last entry of every
+/// part, so clients can check a zip before applying it.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub(crate) struct ZipManifest {
+    pub version: u32,
+    /// The rev an update zip updates from, `None` for content zips.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    pub to: String,
+    pub variant: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bundle: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub added: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub modified: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<String>,
+    /// SHA-256 of every file as zipped, i.e. after rewriting.
+    pub files: BTreeMap<String, String>,
+    /// Set if the zip is split, `files`, `added` and `modified` then only
+    /// name the files of this part and `removed` is only listed by the last.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub part: Option<ZipPart>,
+}
+
+impl ZipManifest {
+    fn add(&mut self, name: &str, buf: &[u8]) -> std::io::Result<()> {
+        if name == ZIP_MANIFEST_FILENAME {
+            return Err(std::io::Error::other(format!(
+                "{name} is reserved for the manifest of the zip"
+            )));
+        }
+        self.files
+            .insert(name.to_string(), format!("{:x}", Sha256::digest(buf)));
+        Ok(())
+    }
+
+    /// The manifest of the part `part` of a zip holding the entries `names`.
+    fn for_part(&self, names: &[String], part: Option<ZipPart>) -> Self {
+        let last = part.is_none_or(|part| part.index == part.count);
+        let in_part = |name: &&String| names.binary_search(name).is_ok();
+        Self {
+            added: self.added.iter().filter(in_part).cloned().collect(),
+            modified: self.modified.iter().filter(in_part).cloned().collect(),
+            removed: if last { self.removed.clone() } else { vec![] },
+            files: self
+                .files
+                .iter()
+                .filter(|(name, _)| in_part(name))
+                .map(|(name, sha256)| (name.clone(), sha256.clone()))
+                .collect(),
+            part,
+            ..self.clone()
+        }
+    }
+
+    /// Upper bound of the size of the manifest of a part without any files.
+    fn base_size(&self) -> std::io::Result<u64> {
+        let empty = Self {
+            added: vec![],
+            modified: vec![],
+            files: BTreeMap::new(),
+            part: Some(ZipPart {
+                index: usize::MAX,
+                count: usize::MAX,
+            }),
+            ..self.clone()
+        };
+        // Slack for the brackets of the lists once they have entries.
+        Ok(serde_json::to_vec_pretty(&empty)?.len() as u64 + 64)
+    }
+
+    /// Upper bound of the size `name` adds to the manifest of a part: an entry
+    /// in `files`, and one in `added` or `modified`.
+    fn entry_size(name: &str) -> u64 {
+        let quoted = serde_json::to_string(name).map_or(6 * name.len() + 2, |q| q.len()) as u64;
+        2 * quoted + 88
+    }
+}
+
+/// Fingerprint of the link rewrites of both variants, changes whenever they
+/// do.
+pub(crate) fn rewrite_fingerprint() -> String {
+    let rules = format!("{APP_REPLACEMENTS:?} {WEB_REPLACEMENTS:?}");
+    format!("{:x}", Sha256::digest(rules))
+}
+
 pub fn replace(input: String, replace: &[(&str, &str)]) -> String {
     let mut result = String::new();
     let mut last_end = 0;
@@ -75,65 +188,136 @@ pub(crate) fn unzip_content(zip_file: &Path, file_name: &str) -> ZipResult<Strin
     Ok(contents)
 }
 
-pub(crate) fn zip_append_buf<T: AsRef<str>, B: AsRef<[u8]>>(
-    zip_file_path: &Path,
-    files: &[(T, B)],
-) -> ZipResult<()> {
-    let file = std::fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(zip_file_path)?;
+/// Zip `files` from `tree` followed by the in-memory files `bufs` and
+/// `manifest` with the hashes of `files`.
+pub(crate) async fn zip_files<T: AsRef<str>>(
+    files: impl Iterator<Item = T>,
+    bufs: &[(&str, &[u8])],
+    tree: &Tree,
+    out_file: &Path,
+    app: bool,
+    config: ZipConfig,
+    mut manifest: ZipManifest,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut zip = PartedZipWriter::new(out_file, config, &manifest)?;
+    let options = SimpleFileOptions::default();
 
-    let mut zip = ZipWriter::new_append(file)?;
-    let options = SimpleFileOptions::default()
-        .compression_method(CompressionMethod::DEFLATE)
-        .unix_permissions(0o644);
+    for path in files {
+        match tree.resolve(path.as_ref()) {
+            Some(full_path) if full_path.is_file() => {
+                let buf = read_entry(full_path.as_path().into(), path.as_ref(), app).await?;
+                manifest.add(path.as_ref(), &buf)?;
+                zip.start_file(path.as_ref(), buf.len() as u64, options)?
+                    .write_all(&buf)?;
+            }
+            _ => zip.add_directory(path.as_ref(), options)?,
+        }
+    }
+    write_bufs(&mut zip, bufs)?;
+    zip.finish(&manifest)
+}
 
-    for (file_name, buf) in files {
-        zip.start_file(file_name.as_ref(), options)?;
-        zip.write_all(buf.as_ref())?;
+/// Counts the bytes written to it.
+#[derive(Default)]
+struct Counter(u64);
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
     }
-    let mut w = zip.finish()?;
-    w.flush()?;
-    Ok(())
 }
 
-pub(crate) async fn zip_files<T: AsRef<str>>(
+/// Size of the file `name` of `tree` deflated as in a web variant zip,
+/// without writing the zip.
+pub(crate) async fn compressed_size(tree: &Tree, name: &str) -> std::io::Result<u64> {
+    match tree.resolve(name) {
+        Some(full_path) if full_path.is_file() => {
+            let buf = read_entry(full_path.as_path().into(), name, false).await?;
+            let mut encoder = DeflateEncoder::new(Counter::default(), Compression::default());
+            encoder.write_all(&buf)?;
+            Ok(encoder.finish()?.0)
+        }
+        _ => Ok(0),
+    }
+}
+
+/// Zip `files` by copying their compressed entries from `archives`, e.g. the
+/// parts of a content zip, followed by the in-memory files `bufs` and
+/// `manifest` with the hashes of `files`.
+pub(crate) fn zip_from_archives<T: AsRef<str>>(
     files: impl Iterator<Item = T>,
-    src_dir: &Path,
+    bufs: &[(&str, &[u8])],
+    archives: &[PathBuf],
     out_file: &Path,
-    app: bool,
-) -> ZipResult<()> {
-    let out_path = Path::new(out_file);
-    let file = std::fs::File::create(out_path)?;
+    config: ZipConfig,
+    mut manifest: ZipManifest,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut sources = open_archives(archives)?;
+    let mut zip = PartedZipWriter::new(out_file, config, &manifest)?;
 
-    let mut zip = ZipWriter::new(file);
-    let options = SimpleFileOptions::default();
+    for name in files {
+        let name = name.as_ref();
+        let source = sources
+            .iter_mut()
+            .find_map(|archive| archive.index_for_name(name).map(|index| (archive, index)));
+        let Some((archive, index)) = source else {
+            return Err(std::io::Error::other(format!(
+                "{name} is missing from {}",
+                archives
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        };
+        let mut file = archive.by_index(index)?;
+        if !file.is_dir() {
+            let mut buf = vec![];
+            file.read_to_end(&mut buf)?;
+            manifest.add(name, &buf)?;
+        }
+        drop(file);
+        zip.raw_copy_file(archive.by_index_raw(index)?)?;
+    }
+    write_bufs(&mut zip, bufs)?;
+    zip.finish(&manifest)
+}
 
-    for path in files {
-        let full_path = src_dir.join(path.as_ref());
-
-        if full_path.is_file().await {
-            zip.start_file(path.as_ref(), options)?;
-
-            if path.as_ref().ends_with("index.json") {
-                let mut buf = read_to_string(full_path).await?;
-                if app {
-                    buf = replace_all_app(buf);
-                } else {
-                    buf = replace_all_web(buf);
-                }
-                zip.write_all(buf.as_bytes())?;
-            } else {
-                let buf = read(full_path).await?;
-                zip.write_all(&buf)?;
-            }
-        } else {
-            zip.add_directory(path.as_ref(), options)?;
+/// Compressed size of every entry in `archives` by name.
+pub(crate) fn entry_sizes(archives: &[PathBuf]) -> std::io::Result<HashMap<String, u64>> {
+    let mut sizes = HashMap::new();
+    for mut archive in open_archives(archives)? {
+        for index in 0..archive.len() {
+            let file = archive.by_index_raw(index)?;
+            sizes.insert(file.name().to_string(), file.compressed_size());
         }
     }
-    let mut w = zip.finish()?;
-    w.flush()?;
+    Ok(sizes)
+}
+
+fn open_archives(paths: &[PathBuf]) -> std::io::Result<Vec<ZipArchive<std::fs::File>>> {
+    let mut archives = vec![];
+    for path in paths {
+        let file = std::fs::File::open(path)
+            .map_err(|e| std::io::Error::other(format!("{}: {e}", path.display())))?;
+        archives.push(ZipArchive::new(file)?);
+    }
+    Ok(archives)
+}
+
+fn write_bufs(zip: &mut PartedZipWriter, bufs: &[(&str, &[u8])]) -> std::io::Result<()> {
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::DEFLATE)
+        .unix_permissions(0o644);
+    for (name, buf) in bufs {
+        zip.start_file(name, buf.len() as u64, options)?
+            .write_all(buf)?;
+    }
     Ok(())
 }
 
@@ -145,43 +329,297 @@ fn replace_all_web(input: String) -> String {
     replace(input, WEB_REPLACEMENTS)
 }
 
-pub(crate) async fn zip_dir(src_dir: &Path, out_file: &Path, app: bool) -> ZipResult<()> {
-    let path = Path::new(out_file);
-    let file = std::fs::File::create(path)?;
+/// Undo the rewrites of an app variant `index.json`. The app URLs are unique,
+/// so unlike the web rewrites these can be reversed.
+pub(crate) fn restore_app(input: String) -> String {
+    let reversed = APP_REPLACEMENTS
+        .iter()
+        .map(|(from, to)| (*to, *from))
+        .collect::<Vec<_>>();
+    replace(input, &reversed)
+}
 
-    let mut zip = ZipWriter::new(file);
+/// Whether a web variant `index.json` may have been rewritten. Rewritten URLs
+/// can't be told apart from relative ones.
+pub(crate) fn maybe_rewritten_web(input: &str) -> bool {
+    input.contains("src=\\\"/")
+}
+
+/// Whether `name` is rewritten by `zip_dir`.
+pub(crate) fn is_rewritten(name: &str) -> bool {
+    name.ends_with("index.json")
+}
+
+pub(crate) async fn zip_dir(
+    tree: &Tree,
+    out_file: &Path,
+    app: bool,
+    config: ZipConfig,
+    exclude: &Exclude,
+    mut manifest: ZipManifest,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut zip = PartedZipWriter::new(out_file, config, &manifest)?;
     let options = SimpleFileOptions::default();
 
-    for entry in WalkDir::new(src_dir).into_iter().filter_map(|e| e.ok()) {
-        let path = entry.path();
-        let name = path.strip_prefix(src_dir).unwrap().to_str().unwrap();
-
-        if path.is_file() {
-            zip.start_file(name, options)?;
-            if name.ends_with("index.json") {
-                let mut buf = read_to_string(path).await?;
-                if app {
-                    buf = replace_all_app(buf);
-                } else {
-                    buf = replace_all_web(buf);
-                }
-                zip.write_all(buf.as_bytes())?;
-            } else {
-                let buf = read(path).await?;
-                zip.write_all(&buf)?;
+    for entry in tree.walk(exclude)? {
+        if entry.is_dir {
+            zip.add_directory(&entry.name, options)?;
+        } else {
+            let buf = read_entry(entry.path.as_path().into(), &entry.name, app).await?;
+            manifest.add(&entry.name, &buf)?;
+            zip.start_file(&entry.name, buf.len() as u64, options)?
+                .write_all(&buf)?;
+        }
+    }
+    zip.finish(&manifest)
+}
+
+async fn read_entry(path: &Path, name: &str, app: bool) -> std::io::Result<Vec<u8>> {
+    if is_rewritten(name) {
+        let mut buf = read_to_string(path).await?;
+        if app {
+            buf = replace_all_app(buf);
+        } else {
+            buf = replace_all_web(buf);
+        }
+        Ok(buf.into_bytes())
+    } else {
+        read(path).await
+    }
+}
+
+/// Path of the `num`th part of a split archive:
+/// `xxx-content.zip` becomes `xxx-content.part-001.zip`.
+pub(crate) fn part_path(out_file: &Path, num: usize) -> PathBuf {
+    let stem = out_file
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    out_file.with_file_name(format!("{stem}.part-{num:03}.zip"))
+}
+
+/// Beyond these limits an archive needs Zip64 extensions.
+const ZIP64_ENTRY_LIMIT: usize = u16::MAX as usize;
+const ZIP64_BYTES_LIMIT: u64 = u32::MAX as u64;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ZipConfig {
+    /// Split archives into standalone parts of at most this many bytes.
+    pub max_part_size: Option<u64>,
+    /// Allow Zip64 archives, which older unzip libraries cannot read.
+    pub zip64: bool,
+}
+
+/// Upper bound of the deflated size of `size` bytes: incompressible data is
+/// stored in blocks with a 5 byte header.
+fn max_compressed_size(size: u64) -> u64 {
+    size + 5 * (size / 16_384 + 1)
+}
+
+/// A zip writer that starts a new, standalone archive whenever the next entry
+/// would push the current one over `max_part_size` or the Zip64 entry limit.
+/// Without a size limit it writes a single archive to `out_file`. Room is kept
+/// in every part for its manifest, which is written once the number of parts
+/// is known. An entry too large for a part of its own is an error.
+struct PartedZipWriter {
+    out_file: PathBuf,
+    config: ZipConfig,
+    parts: Vec<Part>,
+    probe: Option<std::fs::File>,
+    pending: u64,
+    entries: usize,
+    central_dir_size: u64,
+    /// Upper bound of the size of the manifest of the current part.
+    manifest_size: u64,
+    manifest_base_size: u64,
+}
+
+/// An archive of a `PartedZipWriter`, kept open for its manifest.
+struct Part {
+    path: PathBuf,
+    zip: ZipWriter<std::fs::File>,
+    /// The first entry, the only one that may not fit.
+    first: String,
+    names: Vec<String>,
+}
+
+impl PartedZipWriter {
+    fn new(out_file: &Path, config: ZipConfig, manifest: &ZipManifest) -> std::io::Result<Self> {
+        let manifest_base_size = manifest.base_size()?;
+        Ok(Self {
+            out_file: out_file.to_path_buf(),
+            config,
+            parts: vec![],
+            probe: None,
+            pending: 0,
+            entries: 0,
+            central_dir_size: 0,
+            manifest_size: manifest_base_size,
+            manifest_base_size,
+        })
+    }
+
+    fn start_file(
+        &mut self,
+        name: &str,
+        size: u64,
+        options: SimpleFileOptions,
+    ) -> std::io::Result<&mut ZipWriter<std::fs::File>> {
+        let large_file = size >= ZIP64_BYTES_LIMIT;
+        let zip = self.writer(name, size)?;
+        zip.start_file(name, options.large_file(large_file))?;
+        Ok(zip)
+    }
+
+    fn add_directory(&mut self, name: &str, options: SimpleFileOptions) -> std::io::Result<()> {
+        self.writer(name, 0)?.add_directory(name, options)?;
+        Ok(())
+    }
+
+    /// Copy an entry of another archive without recompressing it.
+    fn raw_copy_file<R: Read>(&mut self, file: ZipFile<R>) -> std::io::Result<()> {
+        let name = file.name().to_string();
+        self.writer(&name, file.compressed_size())?
+            .raw_copy_file(file)?;
+        Ok(())
+    }
+
+    fn writer(&mut self, name: &str, size: u64) -> std::io::Result<&mut ZipWriter<std::fs::File>> {
+        // Local header and central directory record are 30 and 46 bytes plus
+        // the name (with a trailing slash for directories) each, and the end
+        // of central directory record is 22 bytes.
+        let entry_size = |name_len: u64, size: u64| 76 + 2 * name_len + max_compressed_size(size);
+        let name_len = name.len() as u64 + 1;
+        let needed = entry_size(name_len, size) + 22;
+        let manifest_entry_size = ZipManifest::entry_size(name);
+        let reserved = |manifest_size: u64| {
+            entry_size(
+                ZIP_MANIFEST_FILENAME.len() as u64,
+                manifest_size + manifest_entry_size,
+            )
+        };
+        // The previous entry is only written out completely once the next one
+        // starts, so count it as pending until then.
+        let mut written = match &self.probe {
+            Some(probe) => probe.metadata()?.len() + self.pending,
+            None => 0,
+        };
+        // One entry of every part is its manifest.
+        if let Some(max) = self.config.max_part_size {
+            if self.entries > 0
+                && (written + self.central_dir_size + needed + reserved(self.manifest_size) > max
+                    || self.entries + 1 >= ZIP64_ENTRY_LIMIT)
+            {
+                self.next_part();
+                written = 0;
             }
-        } else if !name.is_empty() {
-            zip.add_directory(name, options)?;
         }
+        if !self.config.zip64 {
+            if size >= ZIP64_BYTES_LIMIT {
+                return Err(self.zip64_error(&format!("{name} is larger than 4 GiB")));
+            }
+            if self.entries + 1 >= ZIP64_ENTRY_LIMIT {
+                return Err(self.zip64_error("more than 65535 entries"));
+            }
+            if written + self.central_dir_size + needed + reserved(self.manifest_size)
+                > ZIP64_BYTES_LIMIT
+            {
+                return Err(self.zip64_error("archive larger than 4 GiB"));
+            }
+        }
+        if self.probe.is_none() {
+            self.start_part(name)?;
+        }
+        self.entries += 1;
+        self.central_dir_size += 46 + name_len;
+        self.pending = max_compressed_size(size);
+        self.manifest_size += manifest_entry_size;
+        let part = self.parts.last_mut().unwrap();
+        part.names.push(name.to_string());
+        Ok(&mut part.zip)
+    }
+
+    fn zip64_error(&self, reason: &str) -> std::io::Error {
+        let path = self.parts.last().map_or(&self.out_file, |part| &part.path);
+        std::io::Error::other(format!(
+            "{} would need Zip64 ({reason}), use --max-part-size to split it or --zip64 to allow it",
+            path.display()
+        ))
+    }
+
+    fn start_part(&mut self, first: &str) -> std::io::Result<()> {
+        let path = match self.config.max_part_size {
+            Some(_) => part_path(&self.out_file, self.parts.len() + 1),
+            None => self.out_file.clone(),
+        };
+        let file = std::fs::File::create(&path)?;
+        self.probe = Some(file.try_clone()?);
+        self.parts.push(Part {
+            path,
+            zip: ZipWriter::new(file),
+            first: first.to_string(),
+            names: vec![],
+        });
+        Ok(())
+    }
+
+    /// Leave the current part for its manifest, the next entry starts a new
+    /// one.
+    fn next_part(&mut self) {
+        self.probe = None;
+        self.pending = 0;
+        self.entries = 0;
+        self.central_dir_size = 0;
+        self.manifest_size = self.manifest_base_size;
+    }
+
+    /// Write the part of `manifest` of every part and finish them.
+    fn finish(mut self, manifest: &ZipManifest) -> std::io::Result<Vec<PathBuf>> {
+        if self.parts.is_empty() {
+            self.start_part("")?;
+        }
+        let count = self.parts.len();
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::DEFLATE)
+            .unix_permissions(0o644);
+        let mut paths = vec![];
+        for (index, mut part) in self.parts.into_iter().enumerate() {
+            part.names.sort();
+            let info = self.config.max_part_size.map(|_| ZipPart {
+                index: index + 1,
+                count,
+            });
+            let buf = serde_json::to_vec_pretty(&manifest.for_part(&part.names, info))?;
+            part.zip.start_file(ZIP_MANIFEST_FILENAME, options)?;
+            part.zip.write_all(&buf)?;
+            let mut w = part.zip.finish()?;
+            w.flush()?;
+            // Other entries only go into a part if they surely fit, so only
+            // the first can be too large, e.g. for CDNs with a size limit.
+            let size = w.metadata()?.len();
+            if let Some(max) = self.config.max_part_size.filter(|max| size > *max) {
+                return Err(std::io::Error::other(format!(
+                    "{}: {} doesn't fit into a part of {max} bytes ({size} bytes), raise --max-part-size",
+                    part.path.display(),
+                    part.first
+                )));
+            }
+            paths.push(part.path);
+        }
+        Ok(paths)
     }
-    let mut w = zip.finish()?;
-    w.flush()?;
-    Ok(())
 }
 
 #[cfg(test)]
 mod test {
-    use super::replace_all_web;
+    use super::{
+        maybe_rewritten_web, replace_all_app, replace_all_web, restore_app, zip_dir,
+        zip_from_archives, Digest, Sha256, ZipArchive, ZipConfig, ZipManifest, ZipPart,
+        ZIP64_ENTRY_LIMIT, ZIP_MANIFEST_FILENAME,
+    };
+    use crate::exclude::Exclude;
+    use crate::testutil::TempDir;
+    use crate::tree::{Mount, Tree};
 
     #[test]
     fn test_replace_web() {
@@ -191,5 +629,263 @@ mod test {
         let raw = r#"<iframe src=\"https://interactive-examples.mdn.mozilla.net/foo\">"#;
         let out = replace_all_web(raw.to_string());
         assert_eq!(r#"<iframe src=\"/examples/foo\">"#, &out);
+        assert!(maybe_rewritten_web(&out));
+    }
+
+    #[test]
+    fn test_restore_app() {
+        for raw in [
+            r#"<iframe src=\"https://live-samples.mdn.mozilla.net/foo\">"#,
+            r#"<iframe src=\"https://interactive-examples.mdn.mozilla.net/foo\">"#,
+            r#"<iframe src=\"/foo\">"#,
+        ] {
+            assert_eq!(raw, restore_app(replace_all_app(raw.to_string())));
+        }
+    }
+
+    #[async_std::test]
+    async fn test_zip_dir_split() {
+        let dir = TempDir::new("split");
+        let src = dir.join("src");
+        std::fs::create_dir_all(src.join("en-us")).unwrap();
+        std::fs::write(src.join("tiny.txt"), "x").unwrap();
+        for i in 0..10 {
+            let content = format!("{i}").repeat(1000);
+            std::fs::write(src.join("en-us").join(format!("{i}.txt")), content).unwrap();
+        }
+        let out = dir.join("x-content.zip");
+        let config = ZipConfig {
+            max_part_size: Some(2048),
+            ..Default::default()
+        };
+        let parts = zip_dir(
+            &Tree::new(vec![Mount::new(&src, "")]),
+            out.as_path().into(),
+            false,
+            config,
+            &Exclude::default(),
+            ZipManifest::default(),
+        )
+        .await
+        .unwrap();
+        assert!(parts.len() > 1);
+        assert!(!out.exists());
+        let mut files = 0;
+        for (index, part) in parts.iter().enumerate() {
+            assert!(std::fs::metadata(part).unwrap().len() <= 2048);
+            let mut archive = ZipArchive::new(std::fs::File::open(part).unwrap()).unwrap();
+            let mut names = archive
+                .file_names()
+                .filter(|f| f.ends_with(".txt"))
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            files += names.len();
+            // Every part lists its own files.
+            let manifest: ZipManifest =
+                serde_json::from_reader(archive.by_name(ZIP_MANIFEST_FILENAME).unwrap()).unwrap();
+            assert_eq!(
+                manifest.part,
+                Some(ZipPart {
+                    index: index + 1,
+                    count: parts.len()
+                })
+            );
+            let mut listed = manifest.files.into_keys().collect::<Vec<_>>();
+            listed.sort();
+            names.sort();
+            assert_eq!(listed, names);
+        }
+        assert_eq!(files, 11);
+    }
+
+    #[async_std::test]
+    async fn test_zip_dir_manifest() {
+        let dir = TempDir::new("manifest");
+        let src = dir.join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        // Web app builds usually have one of their own.
+        std::fs::write(src.join("manifest.json"), "{}").unwrap();
+        let tree = Tree::new(vec![Mount::new(&src, "")]);
+        let out = dir.join("x-content.zip");
+        let manifest = ZipManifest {
+            to: "x".into(),
+            ..Default::default()
+        };
+        let parts = zip_dir(
+            &tree,
+            out.as_path().into(),
+            false,
+            Default::default(),
+            &Exclude::default(),
+            manifest.clone(),
+        )
+        .await
+        .unwrap();
+        let mut archive = ZipArchive::new(std::fs::File::open(&parts[0]).unwrap()).unwrap();
+        let manifest: ZipManifest =
+            serde_json::from_reader(archive.by_name(ZIP_MANIFEST_FILENAME).unwrap()).unwrap();
+        assert_eq!(manifest.files.keys().collect::<Vec<_>>(), ["manifest.json"]);
+        let own = std::io::read_to_string(archive.by_name("manifest.json").unwrap()).unwrap();
+        assert_eq!(own, "{}");
+
+        std::fs::create_dir(src.join(".differy")).unwrap();
+        std::fs::write(src.join(ZIP_MANIFEST_FILENAME), "{}").unwrap();
+        let err = zip_dir(
+            &tree,
+            out.as_path().into(),
+            false,
+            Default::default(),
+            &Exclude::default(),
+            manifest,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("is reserved"), "{err}");
+    }
+
+    #[async_std::test]
+    async fn test_zip_dir_oversized_entry() {
+        let dir = TempDir::new("oversized");
+        let src = dir.join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(src.join("a.txt"), "a").unwrap();
+        // Incompressible, so it can't fit into 1 KiB.
+        let big = (0..160u32)
+            .flat_map(|i| Sha256::digest(i.to_le_bytes()))
+            .collect::<Vec<_>>();
+        std::fs::write(src.join("big.bin"), big).unwrap();
+        let config = ZipConfig {
+            max_part_size: Some(1024),
+            ..Default::default()
+        };
+        let err = zip_dir(
+            &Tree::new(vec![Mount::new(&src, "")]),
+            dir.join("x-content.zip").as_path().into(),
+            false,
+            config,
+            &Exclude::default(),
+            ZipManifest::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("big.bin doesn't fit"), "{err}");
+    }
+
+    #[async_std::test]
+    async fn test_zip_from_archives() {
+        let dir = TempDir::new("copy");
+        let src = dir.join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(src.join("a.txt"), "a".repeat(100)).unwrap();
+        std::fs::write(src.join("b.txt"), "b").unwrap();
+        let content = dir.join("x-content.zip");
+        let parts = zip_dir(
+            &Tree::new(vec![Mount::new(&src, "")]),
+            content.as_path().into(),
+            false,
+            Default::default(),
+            &Exclude::default(),
+            ZipManifest::default(),
+        )
+        .await
+        .unwrap();
+
+        let out = dir.join("y-x-update.zip");
+        let bufs = [("removed", "c.txt".as_bytes())];
+        let manifest = ZipManifest {
+            from: Some("x".into()),
+            to: "y".into(),
+            removed: vec!["c.txt".into()],
+            ..Default::default()
+        };
+        let copied = zip_from_archives(
+            ["a.txt"].iter(),
+            &bufs,
+            &parts,
+            out.as_path().into(),
+            Default::default(),
+            manifest,
+        )
+        .unwrap();
+        let mut archive = ZipArchive::new(std::fs::File::open(&copied[0]).unwrap()).unwrap();
+        assert_eq!(archive.file_names().count(), 3);
+        let a = std::io::read_to_string(archive.by_name("a.txt").unwrap()).unwrap();
+        assert_eq!(a, "a".repeat(100));
+        let manifest: ZipManifest =
+            serde_json::from_reader(archive.by_name(ZIP_MANIFEST_FILENAME).unwrap()).unwrap();
+        assert_eq!(manifest.removed, ["c.txt"]);
+        assert_eq!(
+            manifest.files.get("a.txt").map(String::as_str),
+            Some(format!("{:x}", Sha256::digest("a".repeat(100))).as_str())
+        );
+
+        let err = zip_from_archives(
+            ["c.txt"].iter(),
+            &[],
+            &parts,
+            out.as_path().into(),
+            Default::default(),
+            ZipManifest::default(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("c.txt is missing"), "{err}");
+    }
+
+    #[async_std::test]
+    async fn test_zip_dir_zip64() {
+        let dir = TempDir::new("zip64");
+        let src = dir.join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        // Directory entries keep this cheap, compressing 65k files is slow in
+        // debug builds.
+        for i in 0..=ZIP64_ENTRY_LIMIT {
+            std::fs::create_dir(src.join(format!("{i}"))).unwrap();
+        }
+        let out = dir.join("x-content.zip");
+
+        let err = zip_dir(
+            &Tree::new(vec![Mount::new(&src, "")]),
+            out.as_path().into(),
+            false,
+            Default::default(),
+            &Exclude::default(),
+            ZipManifest::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("Zip64"), "{err}");
+
+        let config = ZipConfig {
+            zip64: true,
+            ..Default::default()
+        };
+        let parts = zip_dir(
+            &Tree::new(vec![Mount::new(&src, "")]),
+            out.as_path().into(),
+            false,
+            config,
+            &Exclude::default(),
+            ZipManifest::default(),
+        )
+        .await
+        .unwrap();
+        let archive = ZipArchive::new(std::fs::File::open(&parts[0]).unwrap()).unwrap();
+        assert_eq!(archive.len(), ZIP64_ENTRY_LIMIT + 2);
+
+        let config = ZipConfig {
+            max_part_size: Some(u32::MAX as u64),
+            ..Default::default()
+        };
+        let parts = zip_dir(
+            &Tree::new(vec![Mount::new(&src, "")]),
+            out.as_path().into(),
+            false,
+            config,
+            &Exclude::default(),
+            ZipManifest::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(parts.len(), 2);
     }
 }
@@ -1,23 +1,45 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 
 use async_std::{
     fs::{self, read_to_string, File},
     io::prelude::WriteExt,
     path::Path,
 };
-use serde_derive::Serialize;
+use serde_derive::{Deserialize, Serialize};
 
-#[derive(Serialize)]
+use crate::exclude::Exclude;
+
+#[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct Diff {
     pub added: Vec<String>,
     pub removed: Vec<String>,
     pub modified: Vec<String>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Change {
+    Added,
+    Modified,
+    Removed,
+}
+
+impl Change {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Modified => "modified",
+            Change::Removed => "removed",
+        }
+    }
+}
+
 impl Diff {
-    #[allow(dead_code)]
+    /// Read a diff as written by `differy diff` or a `diff.json` of `package`.
     pub async fn from_path(path: &Path) -> std::io::Result<Self> {
         let diff = read_to_string(path).await?;
+        if diff.trim_start().starts_with('{') {
+            return Ok(serde_json::from_str(&diff)?);
+        }
         let mut added = vec![];
         let mut removed = vec![];
         let mut modified = vec![];
@@ -39,6 +61,97 @@ impl Diff {
         })
     }
 
+    fn changes(&self) -> BTreeMap<&str, Change> {
+        let mut changes = BTreeMap::new();
+        for (files, change) in [
+            (&self.added, Change::Added),
+            (&self.modified, Change::Modified),
+            (&self.removed, Change::Removed),
+        ] {
+            changes.extend(files.iter().map(|file| (file.as_str(), change)));
+        }
+        changes
+    }
+
+    fn from_changes(changes: BTreeMap<&str, Change>) -> Self {
+        let mut diff = Diff {
+            added: vec![],
+            removed: vec![],
+            modified: vec![],
+        };
+        for (file, change) in changes {
+            match change {
+                Change::Added => diff.added.push(file.to_string()),
+                Change::Modified => diff.modified.push(file.to_string()),
+                Change::Removed => diff.removed.push(file.to_string()),
+            }
+        }
+        diff
+    }
+
+    /// The diff from the old rev of `self` to the new rev of `next`, which
+    /// must start where `self` ends.
+    pub fn compose(&self, next: &Diff) -> std::io::Result<Diff> {
+        let mut changes = self.changes();
+        for (file, second) in next.changes() {
+            let composed = match (changes.get(file), second) {
+                (None, change) => Some(change),
+                (Some(Change::Added), Change::Modified) => Some(Change::Added),
+                (Some(Change::Added), Change::Removed) => None,
+                (Some(Change::Modified), Change::Modified) => Some(Change::Modified),
+                (Some(Change::Modified), Change::Removed) => Some(Change::Removed),
+                // We can't tell if it came back unchanged.
+                (Some(Change::Removed), Change::Added) => Some(Change::Modified),
+                (Some(first), second) => {
+                    return Err(std::io::Error::other(format!(
+                        "diffs don't chain: {file} is {} and then {}",
+                        first.as_str(),
+                        second.as_str()
+                    )))
+                }
+            };
+            match composed {
+                Some(change) => changes.insert(file, change),
+                None => changes.remove(file),
+            };
+        }
+        Ok(Self::from_changes(changes))
+    }
+
+    /// Check a composed diff against the `actual` one from the checksums.
+    /// Modified files may be unchanged after all, e.g. if a change was
+    /// reverted or a removed file came back.
+    pub fn check(&self, actual: &Diff) -> std::io::Result<()> {
+        let composed = self.changes();
+        let actual = actual.changes();
+        let mut mismatches = vec![];
+        for (file, change) in &composed {
+            match actual.get(file) {
+                Some(actual) if actual == change => {}
+                None if *change == Change::Modified => {}
+                Some(actual) => mismatches.push(format!(
+                    "{file}: {} but {}",
+                    change.as_str(),
+                    actual.as_str()
+                )),
+                None => mismatches.push(format!("{file}: {} but unchanged", change.as_str())),
+            }
+        }
+        for (file, change) in &actual {
+            if !composed.contains_key(file) {
+                mismatches.push(format!("{file}: unchanged but {}", change.as_str()));
+            }
+        }
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(std::io::Error::other(format!(
+                "composed diff doesn't match the checksums:\n{}",
+                mismatches.join("\n")
+            )))
+        }
+    }
+
     pub fn update_iter(&self) -> impl Iterator<Item = &String> {
         self.added.iter().chain(self.modified.iter())
     }
@@ -115,12 +228,53 @@ pub(crate) fn diff<T: AsRef<str>, S: AsRef<str>>(
         modified,
     })
 }
-pub(crate) async fn diff_hash_files(a: &Path, b: &Path) -> std::io::Result<Diff> {
+pub(crate) async fn diff_hash_files(
+    a: &Path,
+    b: &Path,
+    exclude: &Exclude,
+) -> std::io::Result<Diff> {
     let a = fs::read_to_string(a).await?;
     let b = fs::read_to_string(b).await?;
 
-    let a = parse_hashes(&a);
-    let b = parse_hashes(&b);
+    let mut a = parse_hashes(&a);
+    let mut b = parse_hashes(&b);
+    exclude.retain_hashes(&mut a);
+    exclude.retain_hashes(&mut b);
 
     diff(&a, &b)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn changes(added: &[&str], modified: &[&str], removed: &[&str]) -> Diff {
+        let strings = |files: &[&str]| files.iter().map(|f| f.to_string()).collect();
+        Diff {
+            added: strings(added),
+            modified: strings(modified),
+            removed: strings(removed),
+        }
+    }
+
+    #[test]
+    fn test_compose() {
+        let ab = changes(&["new", "temp"], &["mod", "gone"], &["back"]);
+        let bc = changes(&["back", "late"], &["new", "mod"], &["temp", "gone"]);
+        let ac = ab.compose(&bc).unwrap();
+        assert_eq!(ac.added, ["late", "new"]);
+        assert_eq!(ac.modified, ["back", "mod"]);
+        assert_eq!(ac.removed, ["gone"]);
+
+        let err = ab.compose(&changes(&["new"], &[], &[])).unwrap_err();
+        assert!(err.to_string().contains("new is added and then added"));
+
+        let actual = diff(
+            &[("1", "mod"), ("2", "back"), ("3", "gone")],
+            &[("4", "mod"), ("2", "back"), ("5", "late"), ("6", "new")],
+        )
+        .unwrap();
+        ac.check(&actual).unwrap();
+        assert!(ab.check(&actual).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use std::path::Path;
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+/// Gitignore-style file in the build root listing files to leave out.
+pub(crate) const IGNORE_FILENAME: &str = ".differyignore";
+
+/// Files left out of hashing, diffing, content lists and zips.
+#[derive(Clone, Debug)]
+pub(crate) struct Exclude {
+    ignore: Gitignore,
+}
+
+impl Exclude {
+    /// Patterns from `root/.differyignore` plus `patterns` (e.g. `--exclude`).
+    pub fn for_root<P: AsRef<Path>, T: AsRef<str>>(
+        root: P,
+        patterns: &[T],
+    ) -> std::io::Result<Self> {
+        let root = root.as_ref();
+        let mut builder = GitignoreBuilder::new(root);
+        builder
+            .add_line(None, IGNORE_FILENAME)
+            .map_err(std::io::Error::other)?;
+        let ignore_file = root.join(IGNORE_FILENAME);
+        if ignore_file.is_file() {
+            if let Some(e) = builder.add(ignore_file) {
+                return Err(std::io::Error::other(e));
+            }
+        }
+        for pattern in patterns {
+            builder
+                .add_line(None, pattern.as_ref())
+                .map_err(std::io::Error::other)?;
+        }
+        Ok(Self {
+            ignore: builder.build().map_err(std::io::Error::other)?,
+        })
+    }
+
+    /// Only `patterns`, for when there is no build root (e.g. diffing hash files).
+    pub fn from_patterns<T: AsRef<str>>(patterns: &[T]) -> std::io::Result<Self> {
+        let mut builder = GitignoreBuilder::new("");
+        for pattern in patterns {
+            builder
+                .add_line(None, pattern.as_ref())
+                .map_err(std::io::Error::other)?;
+        }
+        Ok(Self {
+            ignore: builder.build().map_err(std::io::Error::other)?,
+        })
+    }
+
+    /// `path` is relative to the build root.
+    pub fn is_excluded<P: AsRef<Path>>(&self, path: P, is_dir: bool) -> bool {
+        let path = path.as_ref();
+        !path.as_os_str().is_empty()
+            && self
+                .ignore
+                .matched_path_or_any_parents(path, is_dir)
+                .is_ignore()
+    }
+
+    pub fn retain_hashes<T: AsRef<str>>(&self, hashes: &mut Vec<(T, T)>) {
+        hashes.retain(|(_, file)| !self.is_excluded(file.as_ref(), false))
+    }
+}
+
+impl Default for Exclude {
+    fn default() -> Self {
+        Self {
+            ignore: Gitignore::empty(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Exclude;
+
+    #[test]
+    fn test_exclude() {
+        let exclude = Exclude::from_patterns(&["*.map", "/allBrowserCompat.txt", "logs/"]).unwrap();
+        assert!(exclude.is_excluded("allBrowserCompat.txt", false));
+        assert!(!exclude.is_excluded("en-us/allBrowserCompat.txt", false));
+        assert!(exclude.is_excluded("static/js/main.js.map", false));
+        assert!(exclude.is_excluded("logs/build.log", false));
+        assert!(!exclude.is_excluded("en-us/docs/web/index.json", false));
+
+        let mut hashes = vec![("a", "main.js"), ("b", "main.js.map")];
+        exclude.retain_hashes(&mut hashes);
+        assert_eq!(hashes, vec![("a", "main.js")]);
+    }
+}
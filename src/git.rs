@@ -0,0 +1,34 @@
+use std::{path::Path, process::Command};
+
+use chrono::{DateTime, FixedOffset};
+
+fn git(repo: &Path, args: &[&str]) -> std::io::Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "git {} in {} failed: {}",
+            args.join(" "),
+            repo.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Committer date of `rev` in the checkout at `repo`.
+pub(crate) fn commit_date(repo: &Path, rev: &str) -> std::io::Result<DateTime<FixedOffset>> {
+    let date = git(
+        repo,
+        &["show", "-s", "--format=%cI", "--end-of-options", rev],
+    )?;
+    DateTime::parse_from_rfc3339(&date).map_err(std::io::Error::other)
+}
+
+/// The commit checked out at `repo`.
+pub(crate) fn head(repo: &Path) -> std::io::Result<String> {
+    git(repo, &["rev-parse", "--verify", "--end-of-options", "HEAD"])
+}
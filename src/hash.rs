@@ -1,26 +1,80 @@
-use async_std::{fs, path::Path};
+use std::io::Read;
+
+use async_std::{fs, path::PathBuf};
 use sha2::Digest;
-use walkdir::WalkDir;
+use zip::ZipArchive;
+
+use crate::{compress, exclude::Exclude, normalize::Normalizer, tree::Tree};
 
 pub(crate) async fn hash_all(
-    dir: &Path,
+    tree: &Tree,
     out: &mut Vec<(String, String)>,
-    base: &Path,
+    exclude: &Exclude,
+    normalizer: &Normalizer,
 ) -> std::io::Result<()> {
-    for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
-        let path = entry.path();
-        if !path.is_dir() {
-            let hash = sha2::Sha256::digest(fs::read(entry.path()).await?);
-            out.push((
-                format!("{hash:x}"),
-                entry
-                    .path()
-                    .strip_prefix(base)
-                    .unwrap()
-                    .to_string_lossy()
-                    .to_string(),
-            ));
+    for entry in tree.walk(exclude)? {
+        if !entry.is_dir {
+            let buf = normalizer.normalize(&entry.name, fs::read(&entry.path).await?);
+            let hash = sha2::Sha256::digest(buf);
+            out.push((format!("{hash:x}"), entry.name));
         }
     }
     Ok(())
 }
+
+/// SHA-256 of the file at `path`, read in chunks.
+pub(crate) fn hash_file(path: impl AsRef<std::path::Path>) -> std::io::Result<String> {
+    let mut hasher = sha2::Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Hashes of the files in a content zip.
+#[derive(Debug, Default)]
+pub(crate) struct ZipHashes {
+    pub hashes: Vec<(String, String)>,
+    /// Rewritten files that couldn't be restored, so their hashes are those
+    /// of the zipped file rather than the original one.
+    pub flagged: Vec<String>,
+}
+
+/// Hashes of the files in `archives`, e.g. the parts of a content zip, as if
+/// they were hashed before zipping. Files rewritten for the `app` variant are
+/// restored, rewritten files of the web variant are flagged.
+pub(crate) fn hash_zips(
+    archives: &[PathBuf],
+    app: bool,
+    normalizer: &Normalizer,
+) -> std::io::Result<ZipHashes> {
+    let mut out = ZipHashes::default();
+    for path in archives {
+        let mut archive = ZipArchive::new(std::fs::File::open(path)?)?;
+        for index in 0..archive.len() {
+            let mut file = archive.by_index(index)?;
+            if file.is_dir() {
+                continue;
+            }
+            let name = file.name().to_string();
+            if name == compress::ZIP_MANIFEST_FILENAME {
+                continue;
+            }
+            let mut buf = vec![];
+            file.read_to_end(&mut buf)?;
+            if compress::is_rewritten(&name) {
+                let text = String::from_utf8(buf).map_err(std::io::Error::other)?;
+                if app {
+                    buf = compress::restore_app(text).into_bytes();
+                } else {
+                    if compress::maybe_rewritten_web(&text) {
+                        out.flagged.push(name.clone());
+                    }
+                    buf = text.into_bytes();
+                }
+            }
+            let buf = normalizer.normalize(&name, buf);
+            let hash = sha2::Sha256::digest(buf);
+            out.hashes.push((format!("{hash:x}"), name));
+        }
+    }
+    Ok(out)
+}
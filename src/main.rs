@@ -1,19 +1,51 @@
+use std::collections::BTreeMap;
+
 use async_std::fs::File;
-use async_std::path::PathBuf;
+use async_std::path::{Path, PathBuf};
 use async_std::prelude::*;
-use chrono::Utc;
-use clap::{crate_version, Arg, Command};
+use chrono::{TimeDelta, Utc};
+use clap::{crate_version, Arg, ArgAction, ArgMatches, Command};
 
-use crate::compress::unzip_content;
-use crate::diff::{diff, parse_hashes};
-use crate::package::package_hashes;
-use crate::package::{package_content, package_update};
-use crate::update::Update;
+use crate::compress::ZipConfig;
+use crate::diff::{parse_hashes, Diff};
+use crate::exclude::Exclude;
+use crate::normalize::Normalizer;
+use crate::package::{
+    apply_zip, archive_paths, fetch_content, is_app_archive, package_files, package_hashes,
+    SkipReason, Skipped,
+};
+use crate::package::{
+    package_content, package_downgrade, package_update, DryRun, DryRunStatus, PlannedUpdate,
+    SizeEstimator, UpdateLimit,
+};
+use crate::provenance::Provenance;
+use crate::publish::Manifest;
+use crate::sign::{Key, PublicKeys};
+use crate::split::Split;
+use crate::staging::{Lock, Staging};
+use crate::store::{read_checksum_zip, ChecksumStore, LocalStore};
+use crate::tree::{Mount, Tree};
+use crate::update::{
+    RevInfo, Update, DEFAULT_CHANNEL, UPDATE_FORMAT_VERSION, ZIP_MANIFEST_REQUIRED_SINCE,
+};
 
 mod compress;
 mod diff;
+mod exclude;
+mod git;
 mod hash;
+mod normalize;
 mod package;
+mod plan;
+mod provenance;
+mod publish;
+mod sign;
+mod split;
+mod staging;
+mod store;
+#[cfg(test)]
+mod testutil;
+mod tree;
 mod update;
 
 const NUM_VERSION_DEFAULT: usize = 14;
@@ -26,13 +58,36 @@ fn cli() -> Command {
         .subcommand(
             Command::new("hash")
                 .about("Hash all files")
-                .arg(Arg::new("PATH").required(true).help("Path to scan"))
+                .arg(
+                    Arg::new("PATH")
+                        .required_unless_present("mount")
+                        .help("Path to scan, or a content zip (or its .parts.json) to hash"),
+                )
+                .arg(
+                    Arg::new("mount")
+                        .long("root")
+                        .action(ArgAction::Append)
+                        .value_parser(Mount::parse)
+                        .help("Additional directory to include as <dir>:<prefix>"),
+                )
                 .arg(
                     Arg::new("out")
                         .long("out")
                         .short('o')
                         .required(true)
-                        .help("Output file"),
+                        .help("Output file, a checksum archive if it ends with .zip"),
+                )
+                .arg(
+                    Arg::new("exclude")
+                        .long("exclude")
+                        .short('x')
+                        .action(ArgAction::Append)
+                        .help("Gitignore-style pattern of files to leave out"),
+                )
+                .arg(
+                    Arg::new("normalize")
+                        .long("normalize")
+                        .help("JSON file with rules to normalize files before hashing"),
                 ),
         )
         .subcommand(
@@ -46,12 +101,55 @@ fn cli() -> Command {
                         .short('o')
                         .required(true)
                         .help("Output file"),
+                )
+                .arg(
+                    Arg::new("exclude")
+                        .long("exclude")
+                        .short('x')
+                        .action(ArgAction::Append)
+                        .help("Gitignore-style pattern of files to leave out"),
+                ),
+        )
+        .subcommand(
+            Command::new("compose")
+                .about("Compose the diffs from A to B and from B to C into one from A to C")
+                .arg(Arg::new("first").required(true).help("Diff from A to B"))
+                .arg(Arg::new("second").required(true).help("Diff from B to C"))
+                .arg(
+                    Arg::new("out")
+                        .long("out")
+                        .short('o')
+                        .required(true)
+                        .help("Output file, written as JSON if it ends with .json"),
+                )
+                .arg(
+                    Arg::new("old_checksums")
+                        .long("old-checksums")
+                        .requires("new_checksums")
+                        .help("Checksum zip of A to check the result against"),
+                )
+                .arg(
+                    Arg::new("new_checksums")
+                        .long("new-checksums")
+                        .requires("old_checksums")
+                        .help("Checksum zip of C to check the result against"),
                 ),
         )
         .subcommand(
             Command::new("package")
                 .about("Package an update zip")
-                .arg(Arg::new("root").required(true).help("Build root"))
+                .arg(
+                    Arg::new("root")
+                        .required_unless_present("mount")
+                        .help("Build root"),
+                )
+                .arg(
+                    Arg::new("mount")
+                        .long("root")
+                        .action(ArgAction::Append)
+                        .value_parser(Mount::parse)
+                        .help("Additional directory to include as <dir>:<prefix>"),
+                )
                 .arg(
                     Arg::new("from")
                         .long("from")
@@ -64,6 +162,12 @@ fn cli() -> Command {
                         .short('n')
                         .help("how many version to support"),
                 )
+                .arg(
+                    Arg::new("max_age")
+                        .long("max-age")
+                        .value_parser(parse_age)
+                        .help("Only support revs packaged within this time (e.g. 30d), capped by --num if given"),
+                )
                 .arg(
                     Arg::new("rev")
                         .long("rev")
@@ -71,25 +175,571 @@ fn cli() -> Command {
                         .required(false)
                         .help("Current rev"),
                 )
-                .arg(Arg::new("out").long("out").short('o').help("Output folder")),
+                .arg(Arg::new("out").long("out").short('o').help("Output folder"))
+                .arg(
+                    Arg::new("locales")
+                        .long("locales")
+                        .action(ArgAction::Append)
+                        .value_delimiter(',')
+                        .help("Also build content and update zips per locale (e.g. en-us,fr)"),
+                )
+                .arg(
+                    Arg::new("channel")
+                        .long("channel")
+                        .default_value(DEFAULT_CHANNEL)
+                        .help("Release channel to package for (e.g. stable or beta)"),
+                )
+                .arg(
+                    Arg::new("content")
+                        .long("content")
+                        .help("Local mdn/content checkout to read commit dates and the provenance commit from"),
+                )
+                .arg(
+                    Arg::new("yari")
+                        .long("yari")
+                        .help("Local mdn/yari checkout the build came from, for the provenance"),
+                )
+                .arg(
+                    Arg::new("interactive_examples")
+                        .long("interactive-examples")
+                        .help("Local mdn/interactive-examples checkout the build came from, for the provenance"),
+                )
+                .arg(
+                    Arg::new("components")
+                        .long("components")
+                        .help("JSON file defining components to build content and update zips for"),
+                )
+                .arg(
+                    Arg::new("exclude")
+                        .long("exclude")
+                        .short('x')
+                        .action(ArgAction::Append)
+                        .help("Gitignore-style pattern of files to leave out"),
+                )
+                .arg(
+                    Arg::new("normalize")
+                        .long("normalize")
+                        .help("JSON file with rules to normalize files before hashing"),
+                )
+                .arg(
+                    Arg::new("max_part_size")
+                        .long("max-part-size")
+                        .value_parser(parse_size)
+                        .help("Split zips into parts of at most this size (e.g. 512M)"),
+                )
+                .arg(
+                    Arg::new("zip64")
+                        .long("zip64")
+                        .action(ArgAction::SetTrue)
+                        .help("Allow zips that need Zip64 (more than 65535 entries or 4 GiB)"),
+                )
+                .arg(
+                    Arg::new("checksums_dir")
+                        .long("checksums-dir")
+                        .default_value(".")
+                        .help("Directory or HTTP(S) base URL with the checksum zips of earlier revs, downloads are cached in --out"),
+                )
+                .arg(
+                    Arg::new("dry_run")
+                        .long("dry-run")
+                        .num_args(0..=1)
+                        .value_parser(["table", "json"])
+                        .default_missing_value("table")
+                        .help("Only print the updates that would be built, as table or json"),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .action(ArgAction::SetTrue)
+                        .help("Package the rev even if it is already the latest of the channel"),
+                )
+                .arg(
+                    Arg::new("strict")
+                        .long("strict")
+                        .action(ArgAction::SetTrue)
+                        .help("Fail if the checksums of an old rev are missing"),
+                )
+                .arg(
+                    Arg::new("rebuild_checksums")
+                        .long("rebuild-checksums")
+                        .action(ArgAction::SetTrue)
+                        .help("Rebuild missing checksums of old revs from their content zips"),
+                )
+                .arg(
+                    Arg::new("max_update_ratio")
+                        .long("max-update-ratio")
+                        .value_parser(parse_ratio)
+                        .help(
+                            "Skip updates larger than this fraction of the content zip (e.g. 0.6)",
+                        ),
+                )
+                .arg(
+                    Arg::new("max_hops")
+                        .long("max-hops")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("4")
+                        .help("Drop update paths of more than this many update zips from update.json"),
+                )
+                .arg(
+                    Arg::new("signing_key")
+                        .long("signing-key")
+                        .action(ArgAction::Append)
+                        .help("Key file to sign the artifacts with (repeat to sign with several keys)"),
+                )
+        )
+        .subcommand(
+            Command::new("promote")
+                .about("Promote the latest rev of a channel to another channel")
+                .arg(Arg::new("rev").required(true).help("Rev to promote"))
+                .arg(
+                    Arg::new("from_channel")
+                        .long("from-channel")
+                        .required(true)
+                        .help("Channel the rev is the latest of"),
+                )
+                .arg(
+                    Arg::new("to_channel")
+                        .long("to-channel")
+                        .default_value(DEFAULT_CHANNEL)
+                        .help("Channel to promote the rev to"),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .short('f')
+                        .default_value("update.json")
+                        .help("update.json to modify"),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .action(ArgAction::SetTrue)
+                        .help("Promote even if clients on the latest rev of --to-channel couldn't update"),
+                ),
+        )
+        .subcommand(
+            Command::new("plan")
+                .about("Show the cheapest chain of update zips from a rev to latest")
+                .arg(
+                    Arg::new("rev")
+                        .long("from")
+                        .required(true)
+                        .help("Rev to update from"),
+                )
+                .arg(
+                    Arg::new("update_json")
+                        .long("update-json")
+                        .short('u')
+                        .default_value("update.json")
+                        .help("update.json to plan with"),
+                )
+                .arg(
+                    Arg::new("channel")
+                        .long("channel")
+                        .default_value(DEFAULT_CHANNEL)
+                        .help("Channel to update in"),
+                )
+                .arg(
+                    Arg::new("variant")
+                        .long("variant")
+                        .value_parser(["web", "app"])
+                        .default_value(plan::PLAN_VARIANT)
+                        .help("Variant of the zips to download"),
+                ),
+        )
+        .subcommand(
+            Command::new("publish")
+                .about("Upload the artifacts of a rev, then update.json")
+                .arg(
+                    Arg::new("rev")
+                        .long("rev")
+                        .short('r')
+                        .required(true)
+                        .help("Rev to publish"),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .required(true)
+                        .help("Local directory or s3://<bucket>[/<prefix>]"),
+                )
+                .arg(
+                    Arg::new("packages")
+                        .long("packages")
+                        .default_value(".")
+                        .help("Directory package wrote the artifacts and manifest to"),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .short('f')
+                        .default_value("update.json")
+                        .help("update.json to publish"),
+                )
+                .arg(
+                    Arg::new("prefix")
+                        .long("prefix")
+                        .default_value("packages")
+                        .help("Folder to upload the artifacts to"),
+                )
+                .arg(
+                    Arg::new("cache_control")
+                        .long("cache-control")
+                        .default_value("public, max-age=86400")
+                        .help("Cache-Control of the artifacts"),
+                )
+                .arg(
+                    Arg::new("cache_control_for")
+                        .long("cache-control-for")
+                        .action(ArgAction::Append)
+                        .value_parser(publish::CacheRule::parse)
+                        .help("Cache-Control of the artifacts matching a glob as <glob>=<value> (e.g. '*.parts.json=public, max-age=300'), the first match wins over --cache-control"),
+                )
+                .arg(
+                    Arg::new("metadata_cache_control")
+                        .long("metadata-cache-control")
+                        .default_value("no-cache")
+                        .help("Cache-Control of update.json and content.json"),
+                )
+                .arg(
+                    Arg::new("s3_endpoint")
+                        .long("s3-endpoint")
+                        .default_value("https://s3.amazonaws.com")
+                        .help("Endpoint of the S3 compatible API"),
+                )
+                .arg(
+                    Arg::new("s3_region")
+                        .long("s3-region")
+                        .default_value("us-east-1")
+                        .help("Region to sign S3 requests for"),
+                )
+                .arg(
+                    Arg::new("signing_key")
+                        .long("signing-key")
+                        .action(ArgAction::Append)
+                        .help("Key file to sign update.json with (repeat to sign with several keys)"),
+                )
+        )
+        .subcommand(
+            Command::new("yank")
+                .about("Withdraw a rev, restoring the previous latest if needed")
+                .arg(Arg::new("rev").required(true).help("Rev to withdraw"))
+                .arg(
+                    Arg::new("channel")
+                        .long("channel")
+                        .default_value(DEFAULT_CHANNEL)
+                        .help("Channel to withdraw the rev from"),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .short('f')
+                        .default_value("update.json")
+                        .help("update.json to modify"),
+                )
+                .arg(
+                    Arg::new("packages")
+                        .long("packages")
+                        .default_value(".")
+                        .help(
+                        "Directory with the checksums, <rev>-update.json and zips of earlier revs",
+                    ),
+                )
+                .arg(
+                    Arg::new("downgrade")
+                        .long("downgrade")
+                        .action(ArgAction::SetTrue)
+                        .help("Build update zips from the withdrawn rev back to the restored one"),
+                )
+                .arg(
+                    Arg::new("out")
+                        .long("out")
+                        .short('o')
+                        .help("Output folder for --downgrade, defaults to --packages so publish finds all artifacts of the restored rev in one place"),
+                )
+                .arg(
+                    Arg::new("max_part_size")
+                        .long("max-part-size")
+                        .value_parser(parse_size)
+                        .help("Split zips into parts of at most this size (e.g. 512M)"),
+                )
+                .arg(
+                    Arg::new("zip64")
+                        .long("zip64")
+                        .action(ArgAction::SetTrue)
+                        .help("Allow zips that need Zip64 extensions"),
+                )
+                .arg(
+                    Arg::new("max_hops")
+                        .long("max-hops")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("4")
+                        .help("Drop update paths of more than this many update zips from update.json"),
+                )
+                .arg(
+                    Arg::new("signing_key")
+                        .long("signing-key")
+                        .action(ArgAction::Append)
+                        .help("Key file to sign the artifacts with (repeat to sign with several keys)"),
+                ),
+        )
+        .subcommand(
+            Command::new("keygen")
+                .about("Generate a key to sign with, and its public key")
+                .arg(
+                    Arg::new("out")
+                        .long("out")
+                        .short('o')
+                        .required(true)
+                        .help("Secret key file to write, the public key goes to <out>.pub"),
+                ),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Check the signatures of update.json and its artifacts")
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .short('f')
+                        .default_value("update.json")
+                        .help("update.json to verify, signed in <from>.<sha256>.sig next to it"),
+                )
+                .arg(
+                    Arg::new("provenance")
+                        .long("provenance")
+                        .help("<rev>-provenance.json to check the artifacts against"),
+                )
+                .arg(
+                    Arg::new("public_key")
+                        .long("public-key")
+                        .required_unless_present("provenance")
+                        .action(ArgAction::Append)
+                        .help("Public key file to trust (repeat to trust several keys)"),
+                )
+                .arg(
+                    Arg::new("packages")
+                        .long("packages")
+                        .help("Directory with artifacts to check against their SHA-256 (with --provenance defaults to its directory)"),
+                )
+                .arg(
+                    Arg::new("allow_missing")
+                        .long("allow-missing")
+                        .action(ArgAction::SetTrue)
+                        .help("Only report artifacts missing from the directory instead of failing"),
+                ),
+        )
+        .subcommand(
+            Command::new("apply")
+                .about("Verify content or update zips and extract them into a directory")
+                .arg(
+                    Arg::new("ZIP")
+                        .required(true)
+                        .num_args(1..)
+                        .help("Zips to apply in order"),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .required(true)
+                        .help("Directory to apply the zips to"),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .short('f')
+                        .default_value("update.json")
+                        .help("update.json listing the zips, signed in <from>.<sha256>.sig next to it"),
+                )
+                .arg(
+                    Arg::new("public_key")
+                        .long("public-key")
+                        .required(true)
+                        .action(ArgAction::Append)
+                        .help("Public key file to trust (repeat to trust several keys)"),
+                ),
         )
 }
 
+fn tree(matches: &ArgMatches, root: &str) -> Tree {
+    let mut mounts = vec![];
+    if let Some(root) = matches.get_one::<String>(root) {
+        mounts.push(Mount::new(root, ""));
+    }
+    if let Some(more) = matches.get_many::<Mount>("mount") {
+        mounts.extend(more.cloned());
+    }
+    Tree::new(mounts)
+}
+
+fn exclude(matches: &ArgMatches, tree: &Tree) -> std::io::Result<Exclude> {
+    match tree.root() {
+        Some(root) => Exclude::for_root(root, &excludes(matches)),
+        None => Exclude::from_patterns(&excludes(matches)),
+    }
+}
+
+fn excludes(matches: &ArgMatches) -> Vec<String> {
+    matches
+        .get_many::<String>("exclude")
+        .map(|patterns| patterns.cloned().collect())
+        .unwrap_or_default()
+}
+
+fn signing_keys(matches: &ArgMatches) -> std::io::Result<Vec<Key>> {
+    matches
+        .get_many::<String>("signing_key")
+        .into_iter()
+        .flatten()
+        .map(|path| Key::from_file(std::path::Path::new(path)))
+        .collect()
+}
+
+fn public_keys(matches: &ArgMatches) -> std::io::Result<PublicKeys> {
+    let paths = matches
+        .get_many::<String>("public_key")
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    PublicKeys::from_files(&paths)
+}
+
+fn normalizer(matches: &ArgMatches) -> std::io::Result<Normalizer> {
+    match matches.get_one::<String>("normalize") {
+        Some(path) => Normalizer::from_file(std::path::Path::new(path)),
+        None => Ok(Normalizer::default()),
+    }
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (num, factor) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    num.parse::<u64>()
+        .map_err(|e| format!("invalid size {s}: {e}"))?
+        .checked_mul(factor)
+        .ok_or_else(|| format!("size {s} is too large"))
+}
+
+fn parse_age(s: &str) -> Result<TimeDelta, String> {
+    let (num, unit) = s.split_at(s.len() - s.chars().last().map_or(0, char::len_utf8));
+    let num = num
+        .parse::<i64>()
+        .map_err(|e| format!("invalid age {s}: {e}"))?;
+    if num <= 0 {
+        return Err(format!("age {s} must be greater than 0"));
+    }
+    let age = match unit {
+        "h" => TimeDelta::try_hours(num),
+        "d" => TimeDelta::try_days(num),
+        "w" => TimeDelta::try_weeks(num),
+        _ => return Err(format!("invalid age {s}: use h, d or w as unit")),
+    };
+    age.ok_or_else(|| format!("age {s} is too large"))
+}
+
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(ratio) if ratio > 0.0 => Ok(ratio),
+        Ok(_) => Err(format!("ratio {s} must be greater than 0")),
+        Err(e) => Err(format!("invalid ratio {s}: {e}")),
+    }
+}
+
+/// Hash the content zip of `rev` in `store` and save the result as its
+/// checksum zip in `out`. The app variant is preferred, as its rewrites can be
+/// undone.
+async fn rebuild_checksums(
+    store: &dyn ChecksumStore,
+    rev: &str,
+    normalizer: &Normalizer,
+    out: &Path,
+) -> std::io::Result<String> {
+    let (archives, app) = match fetch_content(store, rev, true) {
+        Ok(archives) => (archives, true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            (fetch_content(store, rev, false)?, false)
+        }
+        Err(e) => return Err(e),
+    };
+    let zip_hashes = hash::hash_zips(&archives, app, normalizer)?;
+    report_flagged(&zip_hashes.flagged);
+    package_hashes(&zip_hashes.hashes, out, rev).await?;
+    Ok(zip_hashes
+        .hashes
+        .iter()
+        .map(|(hash, file)| format!("{hash} {file}\n"))
+        .collect())
+}
+
+fn report_flagged(flagged: &[String]) {
+    if flagged.is_empty() {
+        return;
+    }
+    println!(
+        "{} rewritten files may not match their originals and will show up as modified:",
+        flagged.len()
+    );
+    for name in flagged {
+        println!("  {name}");
+    }
+}
+
+/// Size and modification time of every file in `tree`, one per line.
+fn tree_stats(tree: &Tree, exclude: &Exclude) -> std::io::Result<String> {
+    let mut stats = String::new();
+    for entry in tree.walk(exclude)? {
+        if entry.is_dir {
+            continue;
+        }
+        let meta = std::fs::metadata(&entry.path)?;
+        let mtime = meta
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        stats.push_str(&format!(
+            "{} {} {}\n",
+            entry.name,
+            meta.len(),
+            mtime.as_nanos()
+        ));
+    }
+    Ok(stats)
+}
+
 #[async_std::main]
 async fn main() -> std::io::Result<()> {
     let matches = cli().get_matches();
 
     if let Some(matches) = matches.subcommand_matches("hash") {
-        let path = matches.get_one::<String>("PATH").unwrap();
+        let tree = tree(matches, "PATH");
         let out = matches.get_one::<String>("out").unwrap();
-        let mut out_file = File::create(out).await?;
-        let path = PathBuf::from(path);
+        let exclude = exclude(matches, &tree)?;
+        let normalizer = normalizer(matches)?;
         let mut hashes = vec![];
-        hash::hash_all(&path, &mut hashes, &path).await?;
-        for (hash, filename) in hashes {
-            out_file
-                .write_all(format!("{hash} {filename}\n").as_bytes())
-                .await?;
+        match matches.get_one::<String>("PATH") {
+            Some(path) if path.ends_with(".zip") || path.ends_with(".parts.json") => {
+                let path = Path::new(path);
+                let app = is_app_archive(&path.file_name().unwrap_or_default().to_string_lossy());
+                let zip_hashes = hash::hash_zips(&archive_paths(path)?, app, &normalizer)?;
+                report_flagged(&zip_hashes.flagged);
+                hashes = zip_hashes.hashes;
+                exclude.retain_hashes(&mut hashes);
+            }
+            _ => hash::hash_all(&tree, &mut hashes, &exclude, &normalizer).await?,
+        }
+        let buf = hashes
+            .iter()
+            .map(|(hash, filename)| format!("{hash} {filename}\n"))
+            .collect::<String>();
+        let out = Path::new(out);
+        if out.extension().is_some_and(|ext| ext == "zip") {
+            let name = out.file_stem().unwrap_or_default().to_string_lossy();
+            compress::zip_content(&name, buf.as_bytes(), out)?;
+        } else {
+            File::create(out).await?.write_all(buf.as_bytes()).await?;
         }
     }
     if let Some(matches) = matches.subcommand_matches("diff") {
@@ -99,31 +749,130 @@ async fn main() -> std::io::Result<()> {
         let mut out_file = File::create(out).await?;
         let old = PathBuf::from(old);
         let new = PathBuf::from(new);
-        let diff = diff::diff_hash_files(&old, &new).await?;
+        let exclude = Exclude::from_patterns(&excludes(matches))?;
+        let diff = diff::diff_hash_files(&old, &new, &exclude).await?;
         diff.write(&mut out_file).await?;
     }
+    if let Some(matches) = matches.subcommand_matches("compose") {
+        let first = PathBuf::from(matches.get_one::<String>("first").unwrap());
+        let second = PathBuf::from(matches.get_one::<String>("second").unwrap());
+        let out = matches.get_one::<String>("out").unwrap();
+        let mut composed = Diff::from_path(&first)
+            .await?
+            .compose(&Diff::from_path(&second).await?)?;
+        if let (Some(old), Some(new)) = (
+            matches.get_one::<String>("old_checksums"),
+            matches.get_one::<String>("new_checksums"),
+        ) {
+            let old = read_checksum_zip(std::path::Path::new(old))?;
+            let new = read_checksum_zip(std::path::Path::new(new))?;
+            let actual = diff::diff(&parse_hashes(&old), &parse_hashes(&new))?;
+            composed.check(&actual)?;
+            // Leaves out files that were removed and came back unchanged.
+            composed = actual;
+        }
+        if out.ends_with(".json") {
+            File::create(out)
+                .await?
+                .write_all(serde_json::to_string(&composed)?.as_bytes())
+                .await?;
+        } else {
+            composed.write(&mut File::create(out).await?).await?;
+        }
+    }
     if let Some(matches) = matches.subcommand_matches("package") {
-        let root = matches.get_one::<String>("root").unwrap();
+        let started = Utc::now();
+        let tree = tree(matches, "root");
         let out = matches
             .get_one::<String>("out")
             .map(|s| s.as_str())
             .unwrap_or(".");
         let current_rev = matches.get_one::<String>("rev").unwrap();
-        let root = PathBuf::from(root);
         let out = PathBuf::from(out);
+        let dry_run = matches.get_one::<String>("dry_run");
+        // A dry run writes nothing but downloaded checksum archives.
+        let _lock = match dry_run {
+            Some(_) => None,
+            None => Some(Lock::acquire(&out)?),
+        };
+        let keys = signing_keys(matches)?;
+        let max_age = matches.get_one::<TimeDelta>("max_age").copied();
         let num_versions = matches
             .get_one::<String>("num_updates")
             .and_then(|s| s.parse::<usize>().ok())
-            .unwrap_or(NUM_VERSION_DEFAULT);
+            .unwrap_or(match max_age {
+                Some(_) => usize::MAX,
+                None => NUM_VERSION_DEFAULT,
+            });
+        let zip_config = ZipConfig {
+            max_part_size: matches.get_one::<u64>("max_part_size").copied(),
+            zip64: matches.get_flag("zip64"),
+        };
+        let locales: Vec<String> = matches
+            .get_many::<String>("locales")
+            .map(|locales| locales.cloned().collect())
+            .unwrap_or_default();
+        let locales = Split::locales(&locales)?;
+        let components = match matches.get_one::<String>("components") {
+            Some(path) => Split::components(std::path::Path::new(path))?,
+            None => Split::default(),
+        };
+        locales.check_disjoint(&components)?;
+        let locale_names = locales.bundle_names();
+        let component_defs = components.defs();
+        let splits: Vec<Split> = [locales, components]
+            .into_iter()
+            .filter(|split| !split.is_empty())
+            .collect();
 
         let from = matches
             .get_one::<String>("from")
             .map(|s| s.as_str())
             .unwrap_or("update.json");
         let update_json = std::path::PathBuf::from(from);
+        let channel = matches.get_one::<String>("channel").unwrap();
+        // Only a missing update.json starts a new one, anything else would be
+        // saved over the existing document below.
+        let mut update_doc = match Update::from_file(&update_json) {
+            Ok(update_doc) => update_doc,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Update::default(),
+            Err(e) => {
+                return Err(std::io::Error::other(format!(
+                    "{}: {e}",
+                    update_json.display()
+                )))
+            }
+        };
+        let published = update_doc
+            .channel(channel)
+            .is_some_and(|update| update.latest.as_ref() == Some(current_rev));
+        if published && !matches.get_flag("force") {
+            // Keep the output of `--dry-run json` parseable.
+            if dry_run.is_some_and(|format| format == "json") {
+                let report = DryRun::published(current_rev);
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!(
+                    "{current_rev} is already the latest of {channel}, use --force to repackage it"
+                );
+            }
+            return Ok(());
+        }
+        // A new channel starts from the revs of the default channel.
+        let previous = update_doc
+            .channel(channel)
+            .or_else(|| update_doc.channel(DEFAULT_CHANNEL))
+            .unwrap_or_default();
+        // The update zips of the previous latest stay part of the graph.
+        let mut graph = previous.graph.clone();
+        plan::add_edges(&mut graph, plan::edges(&previous));
         let Update {
-            updates, latest, ..
-        } = Update::from_file(&update_json).unwrap_or_default();
+            updates,
+            latest,
+            revs: mut old_revs,
+            mut yanked,
+            ..
+        } = previous;
 
         let mut to_be_updated = Vec::new();
         if let Some(latest) = latest {
@@ -131,40 +880,692 @@ async fn main() -> std::io::Result<()> {
                 to_be_updated.push(latest);
             }
         }
-        let take_versions = num_versions - to_be_updated.len();
-        to_be_updated.extend(updates.into_iter().take(take_versions));
+        // The previous latest always gets an update, as its clients were up
+        // to date until now.
+        let now = Utc::now();
+        let mut skipped = vec![];
+        let take_versions = num_versions.saturating_sub(to_be_updated.len());
+        to_be_updated.extend(
+            updates
+                .into_iter()
+                .filter(|rev| rev != current_rev)
+                .filter(
+                    |rev| match (max_age, old_revs.get(rev).and_then(RevInfo::date)) {
+                        (Some(max_age), Some(date)) if now - date > max_age => {
+                            if dry_run.is_none() {
+                                println!("dropping {rev}, packaged {date}");
+                            }
+                            skipped.push(Skipped {
+                                rev: rev.clone(),
+                                reason: SkipReason::MaxAge,
+                                detail: format!("packaged {}", date.to_rfc3339()),
+                            });
+                            false
+                        }
+                        _ => true,
+                    },
+                )
+                .take(take_versions),
+        );
 
+        let exclude = exclude(matches, &tree)?;
+        let normalizer = normalizer(matches)?;
         let mut new_hashes = vec![];
-        hash::hash_all(&root, &mut new_hashes, &root).await?;
-        package_hashes(&new_hashes, &out, current_rev).await?;
+        hash::hash_all(&tree, &mut new_hashes, &exclude, &normalizer).await?;
+        let store = store::open(
+            matches.get_one::<String>("checksums_dir").unwrap(),
+            out.as_ref(),
+        );
+        let ratio = matches.get_one::<f64>("max_update_ratio").copied();
 
-        let mut updated = vec![];
+        if let Some(format) = dry_run {
+            let mut estimator = SizeEstimator::new(&tree);
+            let max = match ratio {
+                Some(ratio) => {
+                    let files = new_hashes.iter().map(|(_, file)| file);
+                    Some((estimator.estimate(files).await? as f64 * ratio) as u64)
+                }
+                None => None,
+            };
+            let mut planned = vec![];
+            for version in to_be_updated {
+                let old_hashes_raw = match store.checksums(&version) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        skipped.push(Skipped {
+                            rev: version,
+                            reason: SkipReason::MissingChecksums,
+                            detail: e.to_string(),
+                        });
+                        continue;
+                    }
+                };
+                let mut old_hashes = parse_hashes(&old_hashes_raw);
+                exclude.retain_hashes(&mut old_hashes);
+                let diff = diff::diff(&old_hashes, &new_hashes)?;
+                let size = estimator.estimate(diff.update_iter()).await?;
+                if let Some(max) = max.filter(|max| size > *max) {
+                    skipped.push(Skipped {
+                        rev: version,
+                        reason: SkipReason::MaxUpdateRatio,
+                        detail: format!("~{size} bytes exceed {max} bytes"),
+                    });
+                    continue;
+                }
+                planned.push(PlannedUpdate {
+                    rev: version,
+                    added: diff.added.len(),
+                    modified: diff.modified.len(),
+                    removed: diff.removed.len(),
+                    size,
+                });
+            }
+            let report = DryRun {
+                rev: current_rev.clone(),
+                status: DryRunStatus::Planned,
+                files: new_hashes.len(),
+                updates: planned,
+                skipped,
+            };
+            match format.as_str() {
+                "json" => println!("{}", serde_json::to_string_pretty(&report)?),
+                _ => report.print_table(),
+            }
+            return Ok(());
+        }
+
+        // Artifacts are moved to `out` once everything is written, steps of a
+        // failed run with the same inputs are reused.
+        let mut staging = Staging::new(&out, current_rev)?;
+        package_hashes(&new_hashes, staging.dir(), current_rev).await?;
+
+        // Resolved before the content zips are built, so a --strict run fails
+        // before the expensive work.
+        let mut old_checksums = vec![];
+        let mut rebuilt = vec![];
         for version in to_be_updated {
-            let checksum_file = format!("{}-checksums", &version);
-            let checksum_zip_file = PathBuf::from(&checksum_file).with_extension("zip");
-            println!("packaging update {current_rev} → {version}");
-            let old_hashes_raw = match unzip_content(&checksum_zip_file, &checksum_file) {
+            let mut checksums = store.checksums(&version);
+            if let (Err(e), true) = (&checksums, matches.get_flag("rebuild_checksums")) {
+                println!("rebuilding checksums of {version} ({e})");
+                checksums = rebuild_checksums(&*store, &version, &normalizer, staging.dir()).await;
+                if checksums.is_ok() {
+                    rebuilt.push(format!("{version}-checksums.zip"));
+                }
+            }
+            if let (Err(e), true) = (&checksums, matches.get_flag("strict")) {
+                return Err(std::io::Error::other(format!(
+                    "no checksums of {version}: {e}"
+                )));
+            }
+            old_checksums.push((version, checksums));
+        }
+
+        let options = format!("{zip_config:?} {splits:?}");
+        // The normalized hashes don't change with the raw bytes the zips are
+        // built from, so a rewritten file must invalidate the steps as well.
+        let new_input = format!("{new_hashes:?} {}", tree_stats(&tree, &exclude)?);
+        let content_input = staging::fingerprint(&[&options, &new_input]);
+        let content = match staging.resume("content", &content_input)? {
+            Some(content) => {
+                println!("reusing content for {current_rev}");
+                content
+            }
+            None => {
+                println!("building content for {current_rev}");
+                let before = staging.files()?;
+                let content = package_content(
+                    &tree,
+                    staging.dir(),
+                    current_rev,
+                    &new_hashes,
+                    zip_config,
+                    &exclude,
+                    &splits,
+                )
+                .await?;
+                staging.record("content", &content_input, &before, &content)?;
+                content
+            }
+        };
+        let limit = match ratio {
+            Some(ratio) => Some(UpdateLimit::new(staging.dir(), &content, ratio)?),
+            None => None,
+        };
+
+        let mut updated = vec![];
+        let mut full_download = vec![];
+        let mut details = BTreeMap::new();
+        for (version, checksums) in old_checksums {
+            let old_hashes_raw = match checksums {
                 Ok(r) => r,
                 Err(e) => {
-                    println!("Error unpacking: {checksum_file:?}.zip: {e}");
+                    println!("Error unpacking: {e}");
+                    skipped.push(Skipped {
+                        rev: version,
+                        reason: SkipReason::MissingChecksums,
+                        detail: e.to_string(),
+                    });
                     continue;
                 }
             };
-            let update_prefix = format!("{}-{}", current_rev, &version);
-            let diff = diff(&parse_hashes(&old_hashes_raw), new_hashes.as_slice())?;
-
-            package_update(&root, &diff, &out, &update_prefix).await?;
+            let mut old_hashes = parse_hashes(&old_hashes_raw);
+            exclude.retain_hashes(&mut old_hashes);
+            if let Some(limit) = &limit {
+                let size = limit.estimate(&old_hashes, &new_hashes)?;
+                if size > limit.max {
+                    println!(
+                        "skipping update {current_rev} → {version}: ~{size} bytes exceed {} bytes",
+                        limit.max
+                    );
+                    skipped.push(Skipped {
+                        rev: version.clone(),
+                        reason: SkipReason::MaxUpdateRatio,
+                        detail: format!("~{size} bytes exceed {} bytes", limit.max),
+                    });
+                    full_download.push(version);
+                    continue;
+                }
+            }
+            let step = format!("update {version}");
+            let input = staging::fingerprint(&[&options, &new_input, &format!("{old_hashes:?}")]);
+            let info = match staging.resume(&step, &input)? {
+                Some(info) => {
+                    println!("reusing update {current_rev} → {version}");
+                    info
+                }
+                None => {
+                    println!("packaging update {current_rev} → {version}");
+                    let before = staging.files()?;
+                    let info = package_update(
+                        &tree,
+                        &old_hashes,
+                        &new_hashes,
+                        staging.dir(),
+                        &version,
+                        current_rev,
+                        zip_config,
+                        &splits,
+                    )
+                    .await?;
+                    staging.record(&step, &input, &before, &info)?;
+                    info
+                }
+            };
+            details.insert(version.clone(), info);
             updated.push(version);
         }
-        println!("building content for {current_rev}");
-        package_content(&root, &out, current_rev, &new_hashes).await?;
 
-        let update = Update {
-            date: Some(Utc::now().naive_utc()),
+        let mut revs = BTreeMap::new();
+        for rev in std::iter::once(current_rev).chain(updated.iter()) {
+            let mut info = old_revs.remove(rev).unwrap_or_default();
+            if rev == current_rev {
+                info.date.get_or_insert(now);
+            }
+            if let (None, Some(content)) = (&info.commit_date, matches.get_one::<String>("content"))
+            {
+                match git::commit_date(std::path::Path::new(content), rev) {
+                    Ok(date) => info.commit_date = Some(date),
+                    Err(e) => println!("No commit date for {rev}: {e}"),
+                }
+            }
+            if info != RevInfo::default() {
+                revs.insert(rev.clone(), info);
+            }
+        }
+
+        yanked.retain(|rev| updated.contains(rev));
+        let mut update = Update {
+            version: UPDATE_FORMAT_VERSION,
+            date: Some(now),
             latest: Some(current_rev.into()),
             updates: updated,
+            locales: locale_names,
+            components: component_defs,
+            content: Some(content),
+            details,
+            revs,
+            yanked,
+            full_download,
+            graph,
+            plans: BTreeMap::new(),
+            channels: BTreeMap::new(),
         };
-        update.save(&update_json)?;
+        plan::update_graph(&mut update, *matches.get_one::<usize>("max_hops").unwrap());
+        if !keys.is_empty() {
+            sign::sign_artifacts(&mut update, &keys);
+        }
+        let mut files = package_files(current_rev, &update);
+        files.extend(rebuilt);
+        update_doc.set_channel(channel, update);
+        // What `yank` restores from if this rev is withdrawn later.
+        let archived_name = format!("{current_rev}-update.json");
+        update_doc.save(staging.dir().join(&archived_name).as_ref())?;
+        files.push(archived_name);
+        // For monitoring, which clients were left without update zips.
+        let skipped_name = format!("{current_rev}-skipped.json");
+        File::create(staging.dir().join(&skipped_name))
+            .await?
+            .write_all(serde_json::to_string_pretty(&skipped)?.as_bytes())
+            .await?;
+
+        let mut commits = BTreeMap::new();
+        for (repo, arg) in [
+            ("content", "content"),
+            ("yari", "yari"),
+            ("interactive-examples", "interactive_examples"),
+        ] {
+            if let Some(checkout) = matches.get_one::<String>(arg) {
+                commits.insert(repo.to_string(), git::head(std::path::Path::new(checkout))?);
+            }
+        }
+        let mut provenance = Provenance {
+            rev: current_rev.clone(),
+            differy_version: crate_version!().to_string(),
+            commits,
+            args: std::env::args().skip(1).collect(),
+            rewrite_rules: compress::rewrite_fingerprint(),
+            tree: provenance::tree_hash(&new_hashes),
+            started,
+            finished: Utc::now(),
+            artifacts: BTreeMap::new(),
+        };
+        let staged: &std::path::Path = staging.dir().as_ref();
+        provenance.add_artifacts(staged, &files)?;
+        provenance.add_artifacts(staged, &[&skipped_name])?;
+        let provenance_name = Provenance::file_name(current_rev);
+        let provenance_out = staged.join(&provenance_name);
+        provenance.save(&provenance_out)?;
+        files.push(provenance_name);
+        if !keys.is_empty() {
+            let signatures = sign::sign_file(&provenance_out, &keys)?;
+            let name = signatures.file_name().unwrap_or_default();
+            files.push(name.to_string_lossy().to_string());
+        }
+        // The file list comes last, as it names the signatures of the
+        // provenance.
+        let manifest = Manifest {
+            rev: current_rev.clone(),
+            files,
+        };
+        manifest.save(
+            staging
+                .dir()
+                .join(Manifest::file_name(current_rev))
+                .as_ref(),
+        )?;
+        // update.json goes last, so it never references missing artifacts.
+        staging.commit()?;
+        update_doc.save(&update_json)?;
+    }
+    if let Some(matches) = matches.subcommand_matches("promote") {
+        let rev = matches.get_one::<String>("rev").unwrap();
+        let from_channel = matches.get_one::<String>("from_channel").unwrap();
+        let to_channel = matches.get_one::<String>("to_channel").unwrap();
+        let update_json = std::path::PathBuf::from(matches.get_one::<String>("from").unwrap());
+        let mut update_doc = Update::from_file(&update_json)?;
+        let promoted = match update_doc.channel(from_channel) {
+            Some(promoted) if promoted.latest.as_ref() == Some(rev) => promoted,
+            _ => {
+                return Err(std::io::Error::other(format!(
+                    "{rev} is not the latest rev of channel {from_channel}"
+                )))
+            }
+        };
+        // Clients on the current latest of the target channel must not be
+        // left without a way to the promoted rev.
+        let stranded = update_doc
+            .channel(to_channel)
+            .and_then(|target| target.latest)
+            .filter(|latest| !promoted.reaches(latest));
+        if let Some(latest) = stranded {
+            if !matches.get_flag("force") {
+                return Err(std::io::Error::other(format!(
+                    "{to_channel} is on {latest}, which {rev} has no update path from, use --force to promote anyway"
+                )));
+            }
+            println!("warning: clients on {latest} can't update to {rev}");
+        }
+        println!("promoting {rev} from {from_channel} to {to_channel}");
+        update_doc.set_channel(to_channel, promoted);
+        update_doc.save(&update_json)?;
+    }
+    if let Some(matches) = matches.subcommand_matches("plan") {
+        let rev = matches.get_one::<String>("rev").unwrap();
+        let channel = matches.get_one::<String>("channel").unwrap();
+        let variant = matches.get_one::<String>("variant").unwrap();
+        let update_json =
+            std::path::PathBuf::from(matches.get_one::<String>("update_json").unwrap());
+        let update = Update::from_file(&update_json)?
+            .channel(channel)
+            .ok_or_else(|| std::io::Error::other(format!("there is no channel {channel}")))?;
+        let Some(latest) = &update.latest else {
+            return Err(std::io::Error::other(format!(
+                "channel {channel} has no latest rev"
+            )));
+        };
+        let full_size = update
+            .content
+            .as_ref()
+            .and_then(|content| content.variants.get(variant))
+            .map(plan::download_size);
+        let mut graph = update.graph.clone();
+        plan::add_edges(&mut graph, plan::edges(&update));
+
+        if rev == latest {
+            println!("{rev} is up to date");
+        } else if let Some(found) = plan::plans(&graph, latest, variant).get(rev) {
+            let mut from = rev;
+            for to in &found.revs {
+                if let Some(artifact) = graph
+                    .iter()
+                    .find(|edge| edge.from == *from && edge.to == *to)
+                    .and_then(|edge| edge.variants.get(variant))
+                {
+                    println!(
+                        "{from} → {to}: {} bytes ({})",
+                        plan::download_size(artifact),
+                        artifact.name
+                    );
+                }
+                from = to;
+            }
+            println!("total: {} bytes", found.size);
+            if let Some(full_size) = full_size.filter(|full_size| *full_size < found.size) {
+                println!("the full download of {full_size} bytes is smaller");
+            }
+        } else {
+            println!("no update from {rev} to {latest}, full download required");
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("publish") {
+        let rev = matches.get_one::<String>("rev").unwrap();
+        let packages = std::path::PathBuf::from(matches.get_one::<String>("packages").unwrap());
+        let update_json = std::path::PathBuf::from(matches.get_one::<String>("from").unwrap());
+        let prefix = matches.get_one::<String>("prefix").unwrap();
+        let cache_control = matches.get_one::<String>("cache_control").unwrap();
+        let metadata_cache_control = matches.get_one::<String>("metadata_cache_control").unwrap();
+        let cache_rules: Vec<publish::CacheRule> = matches
+            .get_many("cache_control_for")
+            .map(|rules| rules.cloned().collect())
+            .unwrap_or_default();
+        let cache_control = |file: &str| publish::cache_control(&cache_rules, file, cache_control);
+        let backend = publish::open(
+            matches.get_one::<String>("to").unwrap(),
+            matches.get_one::<String>("s3_endpoint").unwrap(),
+            matches.get_one::<String>("s3_region").unwrap(),
+        )?;
+        let manifest_name = Manifest::file_name(rev);
+        let manifest = Manifest::from_file(&packages.join(&manifest_name))?;
+        let update_doc = Update::from_file(&update_json)?;
+        let key = |file: &str| match prefix.as_str() {
+            "" => file.to_string(),
+            prefix => format!("{prefix}/{file}"),
+        };
+
+        // Artifacts go first, so update.json never references missing ones.
+        for file in manifest.files.iter().chain(std::iter::once(&manifest_name)) {
+            println!("uploading {file}");
+            backend.put(&key(file), &packages.join(file), cache_control(file))?;
+        }
+        if update_doc.latest.as_ref() == Some(rev) {
+            println!("uploading content.json");
+            let content_json = packages.join(format!("{rev}-content.json"));
+            backend.put("content.json", &content_json, metadata_cache_control)?;
+        }
+        let keys = signing_keys(matches)?;
+        if !keys.is_empty() {
+            // Named after the SHA-256 of update.json and never replaced, so
+            // clients never pair the new signatures with the old document.
+            let signatures = sign::sign_file(&update_json, &keys)?;
+            let name = signatures.file_name().unwrap_or_default().to_string_lossy();
+            println!("uploading {name}");
+            backend.put(&name, &signatures, cache_control(&name))?;
+        }
+        println!("uploading update.json");
+        backend.put("update.json", &update_json, metadata_cache_control)?;
+    }
+    if let Some(matches) = matches.subcommand_matches("yank") {
+        let rev = matches.get_one::<String>("rev").unwrap();
+        let keys = signing_keys(matches)?;
+        let channel = matches.get_one::<String>("channel").unwrap();
+        let update_json = std::path::PathBuf::from(matches.get_one::<String>("from").unwrap());
+        let packages = PathBuf::from(matches.get_one::<String>("packages").unwrap());
+        let out = matches
+            .get_one::<String>("out")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| packages.clone());
+        let _lock = Lock::acquire(&out)?;
+        let mut update_doc = Update::from_file(&update_json)?;
+        let Some(mut update) = update_doc.channel(channel) else {
+            return Err(std::io::Error::other(format!(
+                "there is no channel {channel}"
+            )));
+        };
+        let mut staging = None;
+        if update.latest.as_ref() == Some(rev) {
+            // The archived update.json of the previous latest still describes
+            // its artifacts.
+            let Some(previous) = update
+                .updates
+                .iter()
+                .find(|old| !update.yanked.contains(old))
+                .cloned()
+            else {
+                return Err(std::io::Error::other(format!(
+                    "{rev} has no previous rev to restore"
+                )));
+            };
+            let previous_json = packages.join(format!("{previous}-update.json"));
+            let mut restored = Update::from_file(previous_json.as_path().into())
+                .map_err(|e| std::io::Error::other(format!("{}: {e}", previous_json.display())))?
+                .channel_with_latest(channel, &previous)
+                .ok_or_else(|| {
+                    std::io::Error::other(format!(
+                        "{} does not list {previous} as latest",
+                        previous_json.display()
+                    ))
+                })?;
+            println!("yanking {rev}, restoring {previous} as latest of {channel}");
+            restored.yanked = update.yanked;
+            if matches.get_flag("downgrade") {
+                println!("packaging downgrade {previous} → {rev}");
+                let zip_config = ZipConfig {
+                    max_part_size: matches.get_one::<u64>("max_part_size").copied(),
+                    zip64: matches.get_flag("zip64"),
+                };
+                let store = LocalStore::new(&packages);
+                let mut downgrade = Staging::new(&out, &format!("{previous}-{rev}"))?;
+                let input = staging::fingerprint(&[&format!("{zip_config:?}"), &previous, rev]);
+                let info = match downgrade.resume("downgrade", &input)? {
+                    Some(info) => info,
+                    None => {
+                        let before = downgrade.files()?;
+                        let info = package_downgrade(
+                            &packages,
+                            &store,
+                            &restored,
+                            rev,
+                            downgrade.dir(),
+                            zip_config,
+                        )
+                        .await?;
+                        downgrade.record("downgrade", &input, &before, &info)?;
+                        info
+                    }
+                };
+                // `publish --rev <previous>` uploads the downgrade zips along
+                // with the artifacts of the restored rev.
+                let manifest_name = Manifest::file_name(&previous);
+                let manifest_path = packages.join(&manifest_name);
+                let mut manifest = if manifest_path.exists().await {
+                    Manifest::from_file(manifest_path.as_ref())?
+                } else {
+                    Manifest {
+                        rev: previous.clone(),
+                        files: vec![],
+                    }
+                };
+                for file in downgrade.files()? {
+                    if !manifest.files.contains(&file) {
+                        manifest.files.push(file);
+                    }
+                }
+                manifest.save(downgrade.dir().join(&manifest_name).as_ref())?;
+                staging = Some(downgrade);
+                restored.updates.insert(0, rev.clone());
+                restored.details.insert(rev.clone(), info);
+                if let Some(info) = update.revs.remove(rev) {
+                    restored.revs.insert(rev.clone(), info);
+                }
+                restored.yanked.push(rev.clone());
+            }
+            update = restored;
+        } else if update.updates.contains(rev) {
+            println!("yanking {rev} from the updates of {channel}");
+            update.updates.retain(|old| old != rev);
+            update.details.remove(rev);
+            update.revs.remove(rev);
+        } else {
+            return Err(std::io::Error::other(format!(
+                "{rev} is not listed in channel {channel}"
+            )));
+        }
+        update.yanked.retain(|old| update.updates.contains(old));
+        // Only the downgrade zips may still lead through the yanked rev.
+        update
+            .graph
+            .retain(|edge| edge.from != *rev && edge.to != *rev);
+        plan::update_graph(&mut update, *matches.get_one::<usize>("max_hops").unwrap());
+        if !keys.is_empty() {
+            sign::sign_artifacts(&mut update, &keys);
+        }
+        update.date = Some(Utc::now());
+        // update.json goes last, so it never references missing artifacts.
+        if let Some(staging) = staging {
+            staging.commit()?;
+        }
+        update_doc.set_channel(channel, update);
+        update_doc.save(&update_json)?;
+    }
+    if let Some(matches) = matches.subcommand_matches("keygen") {
+        let out = std::path::Path::new(matches.get_one::<String>("out").unwrap());
+        let id = sign::generate(out)?;
+        println!(
+            "key {id} written to {}, public key to {}",
+            out.display(),
+            sign::public_path(out).display()
+        );
+    }
+    if let Some(matches) = matches.subcommand_matches("verify") {
+        if let Some(path) = matches.get_one::<String>("provenance") {
+            let path = std::path::PathBuf::from(path);
+            if matches.contains_id("public_key") {
+                sign::verify_file(&path, &public_keys(matches)?)?;
+            }
+            let provenance = Provenance::from_file(&path)?;
+            let packages = match matches.get_one::<String>("packages") {
+                Some(packages) => std::path::PathBuf::from(packages),
+                None => path.parent().unwrap_or(std::path::Path::new(".")).into(),
+            };
+            let missing = provenance.verify(&packages)?;
+            for path in &missing {
+                println!("{} is missing", path.display());
+            }
+            if !missing.is_empty() && !matches.get_flag("allow_missing") {
+                return Err(std::io::Error::other(format!(
+                    "{} artifacts of {} are missing, pass --allow-missing to accept that",
+                    missing.len(),
+                    path.display()
+                )));
+            }
+            println!(
+                "{}: {} of {} artifacts checked",
+                path.display(),
+                provenance.artifacts.len() - missing.len(),
+                provenance.artifacts.len()
+            );
+            if !matches.contains_id("public_key") {
+                return Ok(());
+            }
+        }
+        let update_json = std::path::PathBuf::from(matches.get_one::<String>("from").unwrap());
+        let keys = public_keys(matches)?;
+        sign::verify_file(&update_json, &keys)?;
+        let update = Update::from_file(&update_json)?;
+        let artifacts = sign::artifacts(&update)
+            .into_iter()
+            .map(|artifact| (&artifact.name, artifact))
+            .collect::<BTreeMap<_, _>>();
+        let mut checked = 0;
+        let mut missing = 0;
+        for artifact in artifacts.values() {
+            sign::verify_artifact(artifact, &keys)?;
+            let Some(packages) = matches.get_one::<String>("packages") else {
+                continue;
+            };
+            let path = PathBuf::from(packages).join(&artifact.name);
+            if !path.exists().await {
+                println!("{} is missing", path.display());
+                missing += 1;
+                continue;
+            }
+            if hash::hash_file(&path)? != artifact.sha256 {
+                return Err(std::io::Error::other(format!(
+                    "{}: SHA-256 mismatch",
+                    path.display()
+                )));
+            }
+            checked += 1;
+        }
+        if missing > 0 && !matches.get_flag("allow_missing") {
+            return Err(std::io::Error::other(format!(
+                "{missing} artifacts of {} are missing, pass --allow-missing to accept that",
+                update_json.display()
+            )));
+        }
+        println!(
+            "{} and {} artifacts verified, {checked} files checked",
+            update_json.display(),
+            artifacts.len()
+        );
+    }
+    if let Some(matches) = matches.subcommand_matches("apply") {
+        let update_json = std::path::PathBuf::from(matches.get_one::<String>("from").unwrap());
+        let to = PathBuf::from(matches.get_one::<String>("to").unwrap());
+        let keys = public_keys(matches)?;
+        sign::verify_file(&update_json, &keys)?;
+        let update = Update::from_file(&update_json)?;
+        let artifacts = sign::artifacts(&update);
+        // Check everything before touching `to`.
+        let zips = matches
+            .get_many::<String>("ZIP")
+            .unwrap()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        for zip in &zips {
+            let name = zip.file_name().unwrap_or_default().to_string_lossy();
+            let Some(artifact) = artifacts.iter().find(|artifact| artifact.name == name) else {
+                return Err(std::io::Error::other(format!(
+                    "{name} is not listed in {}",
+                    update_json.display()
+                )));
+            };
+            sign::verify_artifact(artifact, &keys)?;
+            if hash::hash_file(zip)? != artifact.sha256 {
+                return Err(std::io::Error::other(format!(
+                    "{}: SHA-256 mismatch",
+                    zip.display()
+                )));
+            }
+        }
+        let require_manifest = update.version >= ZIP_MANIFEST_REQUIRED_SINCE;
+        for zip in &zips {
+            let (written, deleted) = apply_zip(zip, &to, require_manifest)?;
+            println!(
+                "applied {}: {written} files written, {deleted} deleted",
+                zip.display()
+            );
+        }
     }
     Ok(())
 }
@@ -173,3 +1574,39 @@ async fn main() -> std::io::Result<()> {
 fn verify_cli() {
     cli().debug_assert();
 }
+
+#[test]
+fn test_parse_age() {
+    assert_eq!(parse_age("30d"), Ok(TimeDelta::days(30)));
+    assert_eq!(parse_age("12h"), Ok(TimeDelta::hours(12)));
+    assert_eq!(parse_age("2w"), Ok(TimeDelta::days(14)));
+    assert!(parse_age("30").is_err());
+    assert!(parse_age("d").is_err());
+    assert!(parse_age("").is_err());
+    assert!(parse_age("-5d").is_err());
+    assert!(parse_age("0h").is_err());
+    assert_eq!(
+        parse_age("9223372036854775807w"),
+        Err("age 9223372036854775807w is too large".to_string())
+    );
+}
+
+#[test]
+fn test_parse_ratio() {
+    assert_eq!(parse_ratio("0.6"), Ok(0.6));
+    assert!(parse_ratio("0").is_err());
+    assert!(parse_ratio("x").is_err());
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("100"), Ok(100));
+    assert_eq!(parse_size("2K"), Ok(2048));
+    assert_eq!(parse_size("512M"), Ok(512 << 20));
+    assert_eq!(parse_size("1g"), Ok(1 << 30));
+    assert!(parse_size("M").is_err());
+    assert_eq!(
+        parse_size("99999999999G"),
+        Err("size 99999999999G is too large".to_string())
+    );
+}
@@ -0,0 +1,164 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use regex::bytes::Regex;
+use serde_derive::Deserialize;
+use serde_json::Value;
+
+/// Rules file as passed via `--normalize`:
+///
+/// ```json
+/// {
+///   "rules": [
+///     { "files": ["**/index.json"], "drop": ["/doc/modified"] },
+///     { "files": ["**/*.html"], "blank": ["data-build-id=\"[^\"]*\""] }
+///   ]
+/// }
+/// ```
+#[derive(Deserialize, Debug)]
+struct RulesFile {
+    rules: Vec<RawRule>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RawRule {
+    /// Globs of the files the rule applies to, all files if empty.
+    #[serde(default)]
+    files: Vec<String>,
+    /// JSON pointers of values to remove.
+    #[serde(default)]
+    drop: Vec<String>,
+    /// Regexes of bytes to remove.
+    #[serde(default)]
+    blank: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    files: Option<GlobSet>,
+    drop: Vec<String>,
+    blank: Vec<Regex>,
+}
+
+/// Rewrites volatile parts of files (build timestamps, generated ids, …)
+/// before hashing, so re-rendered but otherwise unchanged files don't show
+/// up as modified. Only hashes see the normalized bytes, zips always contain
+/// the files as they are.
+#[derive(Debug, Default)]
+pub(crate) struct Normalizer {
+    rules: Vec<Rule>,
+}
+
+fn invalid<E: std::fmt::Display>(e: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl Normalizer {
+    pub fn from_file(path: &Path) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+        Self::from_rules(serde_json::from_reader(reader)?)
+    }
+
+    fn from_rules(raw: RulesFile) -> std::io::Result<Self> {
+        let mut rules = vec![];
+        for rule in raw.rules {
+            let files = if rule.files.is_empty() {
+                None
+            } else {
+                let mut builder = GlobSetBuilder::new();
+                for glob in &rule.files {
+                    builder.add(Glob::new(glob).map_err(invalid)?);
+                }
+                Some(builder.build().map_err(invalid)?)
+            };
+            let blank = rule
+                .blank
+                .iter()
+                .map(|re| Regex::new(re).map_err(invalid))
+                .collect::<Result<_, _>>()?;
+            rules.push(Rule {
+                files,
+                drop: rule.drop,
+                blank,
+            });
+        }
+        Ok(Self { rules })
+    }
+
+    /// Apply all rules matching `name` (relative to the build root) to `buf`.
+    pub fn normalize(&self, name: &str, mut buf: Vec<u8>) -> Vec<u8> {
+        for rule in &self.rules {
+            if let Some(files) = &rule.files {
+                if !files.is_match(name) {
+                    continue;
+                }
+            }
+            if !rule.drop.is_empty() {
+                if let Ok(mut value) = serde_json::from_slice::<Value>(&buf) {
+                    for pointer in &rule.drop {
+                        drop_pointer(&mut value, pointer);
+                    }
+                    buf = serde_json::to_vec(&value).unwrap_or(buf);
+                }
+            }
+            for re in &rule.blank {
+                if let std::borrow::Cow::Owned(blanked) = re.replace_all(&buf, &b""[..]) {
+                    buf = blanked;
+                }
+            }
+        }
+        buf
+    }
+}
+
+fn drop_pointer(value: &mut Value, pointer: &str) {
+    let Some((parent, key)) = pointer.rsplit_once('/') else {
+        return;
+    };
+    let key = key.replace("~1", "/").replace("~0", "~");
+    match value.pointer_mut(parent) {
+        Some(Value::Object(map)) => {
+            map.remove(&key);
+        }
+        Some(Value::Array(list)) => {
+            if let Ok(i) = key.parse::<usize>() {
+                if i < list.len() {
+                    list.remove(i);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let normalizer = Normalizer::from_rules(
+            serde_json::from_str(
+                r#"{"rules": [
+                    {"files": ["**/index.json"], "drop": ["/doc/modified", "/ids/0"]},
+                    {"blank": ["build-[0-9]+"]}
+                ]}"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        let a = br#"{"doc": {"title": "A", "modified": "2021"}, "ids": [1, 2]}"#;
+        let b = br#"{"doc": {"title": "A", "modified": "2022"}, "ids": [3, 2]}"#;
+        let a = normalizer.normalize("en-us/docs/a/index.json", a.to_vec());
+        let b = normalizer.normalize("en-us/docs/a/index.json", b.to_vec());
+        assert_eq!(a, b);
+        assert_eq!(a, br#"{"doc":{"title":"A"},"ids":[2]}"#);
+
+        let c = normalizer.normalize("main.js", b"x build-123 y".to_vec());
+        assert_eq!(c, b"x  y");
+        let d = br#"{"doc": {"modified": "2022"}}"#;
+        assert_eq!(normalizer.normalize("other.json", d.to_vec()), d);
+    }
+}
@@ -1,11 +1,24 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+
 use async_std::{
-    fs::write,
+    fs::{metadata, write},
     path::{Path, PathBuf},
 };
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zip::ZipArchive;
 
 use crate::{
-    compress::{self, zip_append_buf},
-    diff::Diff,
+    compress::{self, ZipConfig, ZipManifest, ZIP_MANIFEST_FILENAME, ZIP_MANIFEST_VERSION},
+    diff::{diff, parse_hashes, Diff},
+    exclude::Exclude,
+    hash::hash_file,
+    plan,
+    split::Split,
+    store::ChecksumStore,
+    tree::Tree,
+    update::{Artifact, ContentInfo, Update, UpdateInfo, Variants},
 };
 
 const CONTENT_FILENAME: &str = "content.zip";
@@ -13,7 +26,154 @@ const UPDATE_FILENAME: &str = "update.zip";
 const REMOVED_FILENAME: &str = "removed";
 const DIFF_LIST_FILENAME: &str = "diff.json";
 const CONTENT_LIST_FILENAME: &str = "content.json";
-const APP_PREFIX: &str = "app";
+pub(crate) const APP_PREFIX: &str = "app";
+const CONTENT_PARTS_FILENAME: &str = "content.parts.json";
+const UPDATE_PARTS_FILENAME: &str = "update.parts.json";
+const CHECKSUMS_FILENAME: &str = "checksums.zip";
+
+/// Variant names (as used in `update.json`) and whether they are for the app.
+const VARIANTS: [(&str, bool); 2] = [("web", false), ("app", true)];
+
+/// The `<prefix>-content.parts.json` of a split zip.
+#[derive(Deserialize, Serialize)]
+struct PartIndex {
+    parts: Vec<Artifact>,
+}
+
+/// Why an old rev got no update zips, as listed in `<rev>-skipped.json`.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SkipReason {
+    MaxAge,
+    MissingChecksums,
+    MaxUpdateRatio,
+}
+
+impl SkipReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::MaxAge => "max_age",
+            SkipReason::MissingChecksums => "missing_checksums",
+            SkipReason::MaxUpdateRatio => "max_update_ratio",
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Skipped {
+    pub rev: String,
+    pub reason: SkipReason,
+    pub detail: String,
+}
+
+/// An update `package --dry-run` would build.
+#[derive(Serialize, Debug)]
+pub(crate) struct PlannedUpdate {
+    pub rev: String,
+    pub added: usize,
+    pub modified: usize,
+    pub removed: usize,
+    /// Estimated size of the web update zip.
+    pub size: u64,
+}
+
+/// Whether `package --dry-run` would package `rev` at all.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DryRunStatus {
+    Planned,
+    /// `rev` already is the latest of the channel and `--force` wasn't given.
+    Published,
+}
+
+/// What `package --dry-run` would do for `rev`.
+#[derive(Serialize, Debug)]
+pub(crate) struct DryRun {
+    pub rev: String,
+    pub status: DryRunStatus,
+    pub files: usize,
+    pub updates: Vec<PlannedUpdate>,
+    pub skipped: Vec<Skipped>,
+}
+
+impl DryRun {
+    /// The report for a `rev` that is already published.
+    pub fn published(rev: &str) -> Self {
+        Self {
+            rev: rev.to_string(),
+            status: DryRunStatus::Published,
+            files: 0,
+            updates: vec![],
+            skipped: vec![],
+        }
+    }
+
+    pub fn print_table(&self) {
+        println!("{} ({} files)", self.rev, self.files);
+        let width = self
+            .updates
+            .iter()
+            .map(|update| update.rev.len())
+            .chain(self.skipped.iter().map(|skipped| skipped.rev.len()))
+            .chain([3])
+            .max()
+            .unwrap_or_default();
+        println!(
+            "{:width$}  {:>8}  {:>8}  {:>8}  {:>12}",
+            "rev", "added", "modified", "removed", "~bytes"
+        );
+        for update in &self.updates {
+            println!(
+                "{:width$}  {:>8}  {:>8}  {:>8}  {:>12}",
+                update.rev, update.added, update.modified, update.removed, update.size
+            );
+        }
+        for skipped in &self.skipped {
+            println!(
+                "{:width$}  skipped, {}: {}",
+                skipped.rev,
+                skipped.reason.as_str(),
+                skipped.detail
+            );
+        }
+    }
+}
+
+/// Estimates the size of zips without writing them, compressing every file
+/// at most once.
+pub(crate) struct SizeEstimator<'a> {
+    tree: &'a Tree,
+    sizes: HashMap<String, u64>,
+}
+
+impl<'a> SizeEstimator<'a> {
+    pub fn new(tree: &'a Tree) -> Self {
+        Self {
+            tree,
+            sizes: HashMap::new(),
+        }
+    }
+
+    /// Estimated size of a zip of `files`.
+    pub async fn estimate<T: AsRef<str>>(
+        &mut self,
+        files: impl Iterator<Item = T>,
+    ) -> std::io::Result<u64> {
+        let mut total = 0;
+        for file in files {
+            let file = file.as_ref();
+            total += match self.sizes.get(file) {
+                Some(size) => *size,
+                None => {
+                    let size = compress::compressed_size(self.tree, file).await?;
+                    self.sizes.insert(file.to_string(), size);
+                    size
+                }
+            };
+        }
+        Ok(total)
+    }
+}
 
 fn build_path<I: Into<PathBuf>>(base: I, file_name: &str, prefix: &str, app: bool) -> PathBuf {
     let mut full_name = String::new();
@@ -29,46 +189,368 @@ fn build_path<I: Into<PathBuf>>(base: I, file_name: &str, prefix: &str, app: boo
     out
 }
 
-pub(crate) async fn package_update(
-    root: &Path,
+async fn artifact(path: &Path) -> std::io::Result<Artifact> {
+    Ok(Artifact {
+        name: path
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        size: metadata(path).await?.len(),
+        sha256: hash_file(path)?,
+        parts: vec![],
+        signatures: vec![],
+    })
+}
+
+/// Paths of the zips of `artifact` in `dir`, i.e. its parts if it was split.
+fn artifact_paths(dir: &Path, artifact: &Artifact) -> Vec<PathBuf> {
+    if artifact.parts.is_empty() {
+        vec![dir.join(&artifact.name)]
+    } else {
+        artifact
+            .parts
+            .iter()
+            .map(|part| dir.join(&part.name))
+            .collect()
+    }
+}
+
+/// Describe a zip written as `parts`, writing the part index `index_out` if
+/// it was split.
+async fn zip_artifact(
+    parts: &[PathBuf],
+    index_out: &Path,
+    config: ZipConfig,
+) -> std::io::Result<Artifact> {
+    if config.max_part_size.is_none() {
+        return artifact(&parts[0]).await;
+    }
+    let mut artifacts = vec![];
+    for part in parts {
+        artifacts.push(artifact(part).await?);
+    }
+    let index = PartIndex {
+        parts: artifacts.clone(),
+    };
+    write(index_out, serde_json::to_string(&index)?).await?;
+    Ok(Artifact {
+        parts: artifacts,
+        ..artifact(index_out).await?
+    })
+}
+
+/// The manifest of the update zips of `diff` from `from` to `to`, without
+/// variant and files.
+fn update_manifest(diff: &Diff, from: &str, to: &str, bundle: Option<&str>) -> ZipManifest {
+    ZipManifest {
+        version: ZIP_MANIFEST_VERSION,
+        from: Some(from.to_string()),
+        to: to.to_string(),
+        bundle: bundle.map(str::to_string),
+        added: diff.added.clone(),
+        modified: diff.modified.clone(),
+        removed: diff.removed.clone(),
+        ..Default::default()
+    }
+}
+
+/// The manifest of the content zips of `rev`, without variant and files.
+fn content_manifest(rev: &str, bundle: Option<&str>) -> ZipManifest {
+    ZipManifest {
+        version: ZIP_MANIFEST_VERSION,
+        to: rev.to_string(),
+        bundle: bundle.map(str::to_string),
+        ..Default::default()
+    }
+}
+
+/// Update zips from `old` (the hashes of rev `from`) to `new` (those of
+/// `to`), plus one per bundle of every split.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn package_update<T: AsRef<str>, S: AsRef<str>>(
+    tree: &Tree,
+    old: &[(T, T)],
+    new: &[(S, S)],
+    out: &Path,
+    from: &str,
+    to: &str,
+    config: ZipConfig,
+    splits: &[Split],
+) -> std::io::Result<UpdateInfo> {
+    let prefix = format!("{to}-{from}");
+    let diff = diff(old, new)?;
+    let manifest = update_manifest(&diff, from, to, None);
+    let mut info = UpdateInfo {
+        added: diff.added.len(),
+        modified: diff.modified.len(),
+        removed: diff.removed.len(),
+        variants: package_diff(tree, &diff, out, &prefix, config, &manifest).await?,
+        bundles: BTreeMap::new(),
+    };
+    for split in splits {
+        for (bundle, diff) in split.split_diff(old, new)? {
+            let bundle_prefix = format!("{prefix}-{bundle}");
+            let manifest = update_manifest(&diff, from, to, Some(&bundle));
+            let variants =
+                package_diff(tree, &diff, out, &bundle_prefix, config, &manifest).await?;
+            info.bundles.insert(bundle, variants);
+        }
+    }
+    Ok(info)
+}
+
+/// Skips update zips that would be too large compared to the content zip
+/// they update to, see `--max-update-ratio`.
+pub(crate) struct UpdateLimit {
+    sizes: HashMap<String, u64>,
+    pub max: u64,
+}
+
+impl UpdateLimit {
+    /// Allow update zips up to `ratio` of the web content zip in `out`.
+    pub fn new(out: &Path, content: &ContentInfo, ratio: f64) -> std::io::Result<Self> {
+        let Some(web) = content.variants.get(VARIANTS[0].0) else {
+            return Err(std::io::Error::other("no web content zip"));
+        };
+        Ok(Self {
+            sizes: compress::entry_sizes(&artifact_paths(out, web))?,
+            // The size of all parts, `web.size` is that of the part index if
+            // the zip was split.
+            max: (plan::download_size(web) as f64 * ratio) as u64,
+        })
+    }
+
+    /// Estimated size of the update zip from `old` to `new`, i.e. the
+    /// compressed size of its files in the content zip.
+    pub fn estimate<T: AsRef<str>, S: AsRef<str>>(
+        &self,
+        old: &[(T, T)],
+        new: &[(S, S)],
+    ) -> std::io::Result<u64> {
+        let diff = diff(old, new)?;
+        Ok(diff
+            .update_iter()
+            .filter_map(|file| self.sizes.get(file))
+            .sum())
+    }
+}
+
+async fn package_diff(
+    tree: &Tree,
     diff: &Diff,
     out: &Path,
     prefix: &str,
-) -> std::io::Result<()> {
-    let update_out = build_path(out, UPDATE_FILENAME, prefix, false);
-    compress::zip_files(diff.update_iter(), root, &update_out, false).await?;
-    zip_append_buf(
-        &update_out,
-        &[(REMOVED_FILENAME, diff.removed.join("\n").as_bytes())],
-    )?;
-
-    let update_app_out = build_path(out, UPDATE_FILENAME, prefix, true);
-    compress::zip_files(diff.update_iter(), root, &update_app_out, true).await?;
-    zip_append_buf(
-        &update_app_out,
-        &[(REMOVED_FILENAME, diff.removed.join("\n").as_bytes())],
-    )?;
+    config: ZipConfig,
+    manifest: &ZipManifest,
+) -> std::io::Result<Variants> {
+    let mut variants = Variants::new();
+    for (variant, app) in VARIANTS {
+        let update_out = build_path(out, UPDATE_FILENAME, prefix, app);
+        let removed = diff.removed.join("\n");
+        let bufs = [(REMOVED_FILENAME, removed.as_bytes())];
+        let manifest = ZipManifest {
+            variant: variant.to_string(),
+            ..manifest.clone()
+        };
+        let files = diff.update_iter();
+        let parts =
+            compress::zip_files(files, &bufs, tree, &update_out, app, config, manifest).await?;
+        let index_out = build_path(out, UPDATE_PARTS_FILENAME, prefix, app);
+        let artifact = zip_artifact(&parts, &index_out, config).await?;
+        variants.insert(variant.to_string(), artifact);
+    }
 
+    write_diff_lists(diff, out, prefix).await?;
+    Ok(variants)
+}
+
+async fn write_diff_lists(diff: &Diff, out: &Path, prefix: &str) -> std::io::Result<()> {
     let removed_out = build_path(out, REMOVED_FILENAME, prefix, false);
     write(removed_out, diff.removed.join("\n").as_bytes()).await?;
 
     let diff_list_out = build_path(out, DIFF_LIST_FILENAME, prefix, false);
     write(diff_list_out, serde_json::to_string(diff)?).await?;
-
     Ok(())
 }
 
+/// Update zips from the yanked rev `bad` back to `good.latest`, copied from
+/// the content zips of `good` in `archive`, plus one per bundle of `good`.
+/// The checksums of both revs come from `store`.
+pub(crate) async fn package_downgrade(
+    archive: &Path,
+    store: &dyn ChecksumStore,
+    good: &Update,
+    bad: &str,
+    out: &Path,
+    config: ZipConfig,
+) -> std::io::Result<UpdateInfo> {
+    let good_rev = good.latest.as_deref().unwrap_or_default();
+    let old_raw = store.checksums(bad)?;
+    let new_raw = store.checksums(good_rev)?;
+    let old = parse_hashes(&old_raw);
+    let new = parse_hashes(&new_raw);
+    let prefix = format!("{good_rev}-{bad}");
+
+    let diff = diff(&old, &new)?;
+    let manifest = update_manifest(&diff, bad, good_rev, None);
+    let content = good.content.as_ref();
+    let mut info = UpdateInfo {
+        added: diff.added.len(),
+        modified: diff.modified.len(),
+        removed: diff.removed.len(),
+        variants: downgrade_diff(
+            &diff,
+            archive,
+            content.map(|c| &c.variants),
+            good_rev,
+            out,
+            &prefix,
+            config,
+            &manifest,
+        )
+        .await?,
+        bundles: BTreeMap::new(),
+    };
+    let splits = [
+        Split::locales(&good.locales)?,
+        Split::from_defs(good.components.clone())?,
+    ];
+    for split in splits.iter().filter(|split| !split.is_empty()) {
+        for (bundle, diff) in split.split_diff(&old, &new)? {
+            let variants = content
+                .and_then(|c| c.bundles.get(&bundle))
+                .ok_or_else(|| {
+                    std::io::Error::other(format!("no {bundle} content zips of {good_rev}"))
+                })?;
+            let manifest = update_manifest(&diff, bad, good_rev, Some(&bundle));
+            let variants = downgrade_diff(
+                &diff,
+                archive,
+                Some(variants),
+                &format!("{good_rev}-{bundle}"),
+                out,
+                &format!("{prefix}-{bundle}"),
+                config,
+                &manifest,
+            )
+            .await?;
+            info.bundles.insert(bundle, variants);
+        }
+    }
+    Ok(info)
+}
+
+/// Like `package_diff`, but copies the files from the `content` zips named
+/// `content_prefix`, which are found in `archive`.
+#[allow(clippy::too_many_arguments)]
+async fn downgrade_diff(
+    diff: &Diff,
+    archive: &Path,
+    content: Option<&Variants>,
+    content_prefix: &str,
+    out: &Path,
+    prefix: &str,
+    config: ZipConfig,
+    manifest: &ZipManifest,
+) -> std::io::Result<Variants> {
+    let mut variants = Variants::new();
+    for (variant, app) in VARIANTS {
+        // Files from before `update.json` described the content zips are
+        // single zips.
+        let sources = match content.and_then(|c| c.get(variant)) {
+            Some(artifact) => artifact_paths(archive, artifact),
+            None => vec![build_path(archive, CONTENT_FILENAME, content_prefix, app)],
+        };
+        let update_out = build_path(out, UPDATE_FILENAME, prefix, app);
+        let removed = diff.removed.join("\n");
+        let bufs = [(REMOVED_FILENAME, removed.as_bytes())];
+        let manifest = ZipManifest {
+            variant: variant.to_string(),
+            ..manifest.clone()
+        };
+        let files = diff.update_iter();
+        let parts =
+            compress::zip_from_archives(files, &bufs, &sources, &update_out, config, manifest)?;
+        let index_out = build_path(out, UPDATE_PARTS_FILENAME, prefix, app);
+        let artifact = zip_artifact(&parts, &index_out, config).await?;
+        variants.insert(variant.to_string(), artifact);
+    }
+    write_diff_lists(diff, out, prefix).await?;
+    Ok(variants)
+}
+
 pub(crate) async fn package_content<T: AsRef<str>>(
-    root: &Path,
+    tree: &Tree,
     out: &Path,
     prefix: &str,
     hashes: &[(T, T)],
-) -> std::io::Result<()> {
-    let content_out = build_path(out, CONTENT_FILENAME, prefix, false);
-    compress::zip_dir(root, &content_out, false).await?;
-    let content_app_out = build_path(out, CONTENT_FILENAME, prefix, true);
-    compress::zip_dir(root, &content_app_out, true).await?;
+    config: ZipConfig,
+    exclude: &Exclude,
+    splits: &[Split],
+) -> std::io::Result<ContentInfo> {
+    let mut info = ContentInfo {
+        files: hashes.len(),
+        ..Default::default()
+    };
+    for (variant, app) in VARIANTS {
+        let content_out = build_path(out, CONTENT_FILENAME, prefix, app);
+        let manifest = ZipManifest {
+            variant: variant.to_string(),
+            ..content_manifest(prefix, None)
+        };
+        let parts = compress::zip_dir(tree, &content_out, app, config, exclude, manifest).await?;
+        let index_out = build_path(out, CONTENT_PARTS_FILENAME, prefix, app);
+        let artifact = zip_artifact(&parts, &index_out, config).await?;
+        info.variants.insert(variant.to_string(), artifact);
+    }
+    write_content_list(out, prefix, hashes).await?;
 
+    for split in splits {
+        for (bundle, hashes) in split.split_hashes(hashes) {
+            println!("building {bundle} content for {prefix}");
+            let bundle_prefix = format!("{prefix}-{bundle}");
+            let manifest = content_manifest(prefix, Some(&bundle));
+            let variants =
+                package_bundle_content(tree, out, &bundle_prefix, &hashes, config, &manifest)
+                    .await?;
+            info.bundles.insert(bundle, variants);
+        }
+    }
+    Ok(info)
+}
+
+/// Content zips of a bundle, i.e. only the files in `hashes`.
+async fn package_bundle_content<T: AsRef<str>>(
+    tree: &Tree,
+    out: &Path,
+    prefix: &str,
+    hashes: &[(T, T)],
+    config: ZipConfig,
+    manifest: &ZipManifest,
+) -> std::io::Result<Variants> {
+    let mut variants = Variants::new();
+    for (variant, app) in VARIANTS {
+        let content_out = build_path(out, CONTENT_FILENAME, prefix, app);
+        let files = hashes.iter().map(|(_, f)| f.as_ref());
+        let manifest = ZipManifest {
+            variant: variant.to_string(),
+            ..manifest.clone()
+        };
+        let parts =
+            compress::zip_files(files, &[], tree, &content_out, app, config, manifest).await?;
+        let index_out = build_path(out, CONTENT_PARTS_FILENAME, prefix, app);
+        let artifact = zip_artifact(&parts, &index_out, config).await?;
+        variants.insert(variant.to_string(), artifact);
+    }
+    write_content_list(out, prefix, hashes).await?;
+    Ok(variants)
+}
+
+async fn write_content_list<T: AsRef<str>>(
+    out: &Path,
+    prefix: &str,
+    hashes: &[(T, T)],
+) -> std::io::Result<()> {
     let content_list_out = build_path(out, CONTENT_LIST_FILENAME, prefix, false);
     let list = hashes
         .iter()
@@ -78,6 +560,195 @@ pub(crate) async fn package_content<T: AsRef<str>>(
     Ok(())
 }
 
+/// Local paths of the content zip of `rev` in `store`, i.e. its parts if it
+/// was split.
+pub(crate) fn fetch_content(
+    store: &dyn ChecksumStore,
+    rev: &str,
+    app: bool,
+) -> std::io::Result<Vec<PathBuf>> {
+    let content = build_path("", CONTENT_FILENAME, rev, app);
+    match store.fetch(&content.to_string_lossy()) {
+        Ok(path) => Ok(vec![path.into()]),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let index = build_path("", CONTENT_PARTS_FILENAME, rev, app);
+            let index = std::fs::read(store.fetch(&index.to_string_lossy())?)?;
+            let index: PartIndex = serde_json::from_slice(&index)?;
+            index
+                .parts
+                .iter()
+                .map(|part| store.fetch(&part.name).map(PathBuf::from))
+                .collect()
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The archives of the zip at `path`: the zip itself, or the parts listed in
+/// a part index.
+pub(crate) fn archive_paths(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    if !file_name.ends_with(".parts.json") {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let index: PartIndex = serde_json::from_slice(&std::fs::read(path)?)?;
+    Ok(index
+        .parts
+        .iter()
+        .map(|part| path.with_file_name(&part.name))
+        .collect())
+}
+
+/// Whether the zip `file_name` is of the app variant.
+pub(crate) fn is_app_archive(file_name: &str) -> bool {
+    file_name.contains(&format!("-{APP_PREFIX}-"))
+}
+
+/// The manifest of a content or update zip, `None` for zips written before
+/// zips had one, or before every part had one.
+pub(crate) fn read_zip_manifest<R: std::io::Read + std::io::Seek>(
+    archive: &mut ZipArchive<R>,
+) -> std::io::Result<Option<ZipManifest>> {
+    match archive.by_name(ZIP_MANIFEST_FILENAME) {
+        Ok(file) => Ok(Some(serde_json::from_reader(file)?)),
+        Err(zip::result::ZipError::FileNotFound) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Check that every file of `archive` is listed in `manifest` with its
+/// SHA-256, and since version 2 that every file listed is in `archive`.
+fn check_zip_manifest<R: std::io::Read + std::io::Seek>(
+    archive: &mut ZipArchive<R>,
+    manifest: &ZipManifest,
+) -> std::io::Result<()> {
+    if manifest.version >= 2 {
+        if let Some(name) = manifest
+            .files
+            .keys()
+            .find(|name| archive.index_for_name(name).is_none())
+        {
+            return Err(std::io::Error::other(format!("{name}: missing from zip")));
+        }
+    }
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        let name = file.name().to_string();
+        if file.is_dir() || name == REMOVED_FILENAME || name == ZIP_MANIFEST_FILENAME {
+            continue;
+        }
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher)?;
+        match manifest.files.get(&name) {
+            Some(sha256) if *sha256 == format!("{:x}", hasher.finalize()) => {}
+            Some(_) => return Err(std::io::Error::other(format!("{name}: checksum mismatch"))),
+            None => return Err(std::io::Error::other(format!("{name}: not in manifest"))),
+        }
+    }
+    Ok(())
+}
+
+/// Extract the content or update zip `zip` into `dir` and delete the files
+/// its `removed` list names. The files are checked against its manifest
+/// first, which is an error to be missing if `require_manifest`. Returns the
+/// number of written and deleted files.
+pub(crate) fn apply_zip(
+    zip: &Path,
+    dir: &Path,
+    require_manifest: bool,
+) -> std::io::Result<(usize, usize)> {
+    let dir: &std::path::Path = dir.as_ref();
+    let unsafe_path =
+        |name: &str| std::io::Error::other(format!("{}: unsafe path {name}", zip.display()));
+    let mut archive = ZipArchive::new(std::fs::File::open(zip)?)?;
+    match read_zip_manifest(&mut archive)? {
+        Some(manifest) => check_zip_manifest(&mut archive, &manifest)
+            .map_err(|e| std::io::Error::other(format!("{}: {e}", zip.display())))?,
+        None if require_manifest => {
+            return Err(std::io::Error::other(format!(
+                "{}: {ZIP_MANIFEST_FILENAME} is missing",
+                zip.display()
+            )))
+        }
+        None => {}
+    }
+    let mut written = 0;
+    let mut removed = String::new();
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        if file.name() == REMOVED_FILENAME {
+            file.read_to_string(&mut removed)?;
+            continue;
+        }
+        if file.name() == ZIP_MANIFEST_FILENAME {
+            continue;
+        }
+        let Some(name) = file.enclosed_name() else {
+            return Err(unsafe_path(file.name()));
+        };
+        let out = dir.join(name);
+        if file.is_dir() {
+            std::fs::create_dir_all(out)?;
+            continue;
+        }
+        if let Some(parent) = out.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::io::copy(&mut file, &mut std::fs::File::create(out)?)?;
+        written += 1;
+    }
+    let mut deleted = 0;
+    for name in removed.lines().filter(|name| !name.is_empty()) {
+        let relative = std::path::Path::new(name);
+        if !relative
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+        {
+            return Err(unsafe_path(name));
+        }
+        match std::fs::remove_file(dir.join(relative)) {
+            Ok(()) => deleted += 1,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((written, deleted))
+}
+
+/// Names of all files `package` wrote to `--out` for `rev`, the latest rev of
+/// `update`.
+pub(crate) fn package_files(rev: &str, update: &Update) -> Vec<String> {
+    let name = |file_name: &str, prefix: &str| format!("{prefix}-{file_name}");
+    let mut files = vec![name(CHECKSUMS_FILENAME, rev)];
+    let mut artifacts = vec![];
+    if let Some(content) = &update.content {
+        files.push(name(CONTENT_LIST_FILENAME, rev));
+        artifacts.extend(content.variants.values());
+        for (bundle, variants) in &content.bundles {
+            files.push(name(CONTENT_LIST_FILENAME, &format!("{rev}-{bundle}")));
+            artifacts.extend(variants.values());
+        }
+    }
+    for (old, info) in &update.details {
+        let prefix = format!("{rev}-{old}");
+        let bundle_prefixes = info
+            .bundles
+            .keys()
+            .map(|bundle| format!("{prefix}-{bundle}"));
+        for prefix in std::iter::once(prefix.clone()).chain(bundle_prefixes) {
+            files.push(name(REMOVED_FILENAME, &prefix));
+            files.push(name(DIFF_LIST_FILENAME, &prefix));
+        }
+        artifacts.extend(info.variants.values());
+        artifacts.extend(info.bundles.values().flat_map(|variants| variants.values()));
+    }
+    for artifact in artifacts {
+        files.push(artifact.name.clone());
+        files.extend(artifact.parts.iter().map(|part| part.name.clone()));
+    }
+    files
+}
+
 pub(crate) async fn package_hashes<T: AsRef<str>>(
     hashes: &[(T, T)],
     out: &Path,
@@ -87,10 +758,101 @@ pub(crate) async fn package_hashes<T: AsRef<str>>(
     for (hash, file) in hashes {
         buf.extend(format!("{} {}\n", hash.as_ref(), file.as_ref()).as_bytes())
     }
-    let file_name = build_path("", "checksums", prefix, false);
+    let file_name = build_path("", CHECKSUMS_FILENAME, prefix, false);
     let mut out_file_name = out.to_path_buf();
     out_file_name.push(&file_name);
-    out_file_name.set_extension("zip");
+    let file_name = file_name.with_extension("");
     compress::zip_content(file_name.to_str().unwrap(), &buf, &out_file_name)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+    use crate::{hash::hash_all, normalize::Normalizer, tree::Mount};
+
+    #[async_std::test]
+    async fn test_update_limit_split() {
+        let dir = TempDir::new("limit");
+        let src = dir.join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        // Incompressible, so the parts add up to roughly the files.
+        for i in 0..8u64 {
+            let buf = (0..128u64)
+                .flat_map(|j| Sha256::digest((i * 128 + j).to_le_bytes()))
+                .collect::<Vec<_>>();
+            std::fs::write(src.join(format!("{i}.bin")), buf).unwrap();
+        }
+        let tree = Tree::new(vec![Mount::new(&src, "")]);
+        let mut hashes = vec![];
+        hash_all(
+            &tree,
+            &mut hashes,
+            &Exclude::default(),
+            &Normalizer::default(),
+        )
+        .await
+        .unwrap();
+        let out = PathBuf::from(dir.join("out"));
+        std::fs::create_dir_all(&out).unwrap();
+        let config = ZipConfig {
+            max_part_size: Some(8192),
+            ..Default::default()
+        };
+        let content = package_content(&tree, &out, "b", &hashes, config, &Exclude::default(), &[])
+            .await
+            .unwrap();
+        let web = &content.variants["web"];
+        assert!(web.parts.len() > 1);
+
+        let limit = UpdateLimit::new(&out, &content, 0.5).unwrap();
+        assert_eq!(limit.max, plan::download_size(web) / 2);
+        assert!(limit.max > 16 * 1024);
+        // One changed file of eight stays well below half of the content.
+        let mut old = hashes.clone();
+        old[0].0 = "00".into();
+        let size = limit.estimate(&old, &hashes).unwrap();
+        assert!(size > 4096 && size < limit.max, "{size}");
+    }
+
+    #[async_std::test]
+    async fn test_apply_zip_manifest() {
+        let dir = TempDir::new("apply");
+        let src = dir.join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        for i in 0..4 {
+            std::fs::write(src.join(format!("{i}.txt")), format!("{i}").repeat(1000)).unwrap();
+        }
+        let tree = Tree::new(vec![Mount::new(&src, "")]);
+        let out = PathBuf::from(dir.join("out"));
+        std::fs::create_dir_all(&out).unwrap();
+        let config = ZipConfig {
+            max_part_size: Some(1024),
+            ..Default::default()
+        };
+        let parts = compress::zip_dir(
+            &tree,
+            &out.join("b-content.zip"),
+            false,
+            config,
+            &Exclude::default(),
+            content_manifest("b", None),
+        )
+        .await
+        .unwrap();
+        assert!(parts.len() > 1);
+        let to = PathBuf::from(dir.join("to"));
+        let mut written = 0;
+        for part in &parts {
+            written += apply_zip(part, &to, true).unwrap().0;
+        }
+        assert_eq!(written, 4);
+
+        let bare = out.join("a-content.zip");
+        compress::zip_content("0.txt", b"0", &bare).unwrap();
+        let err = apply_zip(&bare, &to, true).unwrap_err();
+        assert!(err.to_string().contains("is missing"), "{err}");
+        assert_eq!(apply_zip(&bare, &to, false).unwrap(), (1, 0));
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap},
+};
+
+use crate::update::{Artifact, Edge, Plan, Update};
+
+/// Variant whose sizes the plans in `update.json` are based on.
+pub(crate) const PLAN_VARIANT: &str = "web";
+
+/// Bytes to download for `artifact`, i.e. its parts if it was split.
+pub(crate) fn download_size(artifact: &Artifact) -> u64 {
+    if artifact.parts.is_empty() {
+        artifact.size
+    } else {
+        artifact.parts.iter().map(|part| part.size).sum()
+    }
+}
+
+/// The update zips of `update.details` as edges to its `latest`.
+pub(crate) fn edges(update: &Update) -> Vec<Edge> {
+    let Some(latest) = &update.latest else {
+        return vec![];
+    };
+    update
+        .details
+        .iter()
+        .map(|(from, info)| Edge {
+            from: from.clone(),
+            to: latest.clone(),
+            variants: info.variants.clone(),
+        })
+        .collect()
+}
+
+/// The cheapest chain from every rev in `graph` to `latest` by the size of
+/// the `variant` zips, using Dijkstra's algorithm on the reversed graph.
+pub(crate) fn plans(graph: &[Edge], latest: &str, variant: &str) -> BTreeMap<String, Plan> {
+    let mut into: HashMap<&str, Vec<(&Edge, u64)>> = HashMap::new();
+    for edge in graph {
+        if let Some(artifact) = edge.variants.get(variant) {
+            into.entry(edge.to.as_str())
+                .or_default()
+                .push((edge, download_size(artifact)));
+        }
+    }
+
+    // Size of the cheapest chain to `latest` and its first hop, per rev.
+    let mut best: HashMap<&str, (u64, Option<&str>)> = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    best.insert(latest, (0, None));
+    queue.push(Reverse((0, latest)));
+    while let Some(Reverse((size, rev))) = queue.pop() {
+        if best
+            .get(rev)
+            .is_some_and(|(best_size, _)| *best_size < size)
+        {
+            continue;
+        }
+        for (edge, edge_size) in into.get(rev).into_iter().flatten() {
+            let size = size + edge_size;
+            let from = edge.from.as_str();
+            if best
+                .get(from)
+                .is_none_or(|(best_size, _)| size < *best_size)
+            {
+                best.insert(from, (size, Some(rev)));
+                queue.push(Reverse((size, from)));
+            }
+        }
+    }
+
+    let mut plans = BTreeMap::new();
+    for (&from, &(size, _)) in &best {
+        if from == latest {
+            continue;
+        }
+        let mut revs = vec![];
+        let mut rev = from;
+        while let Some((_, Some(next))) = best.get(rev) {
+            revs.push(next.to_string());
+            rev = next;
+        }
+        plans.insert(from.to_string(), Plan { revs, size });
+    }
+    plans
+}
+
+/// Add `edges` to `graph`, replacing edges between the same revs.
+pub(crate) fn add_edges(graph: &mut Vec<Edge>, edges: Vec<Edge>) {
+    for edge in edges {
+        graph.retain(|old| (&old.from, &old.to) != (&edge.from, &edge.to));
+        graph.push(edge);
+    }
+}
+
+/// Add the update zips of `update.details` to its graph and plan the
+/// cheapest chains. Revs more than `max_hops` update zips away from `latest`
+/// lose their plan (their clients download the content zips), and only the
+/// edges some plan uses are kept, so the graph doesn't grow with every
+/// release.
+pub(crate) fn update_graph(update: &mut Update, max_hops: usize) {
+    let Some(latest) = update.latest.clone() else {
+        return;
+    };
+    let edges = edges(update);
+    add_edges(&mut update.graph, edges);
+    let mut plans = plans(&update.graph, &latest, PLAN_VARIANT);
+    plans.retain(|_, plan| plan.revs.len() <= max_hops);
+    // The rest of a plan is the plan of its next rev, so the first hops of
+    // all plans are all edges needed.
+    update.graph.retain(|edge| {
+        plans
+            .get(&edge.from)
+            .is_some_and(|plan| plan.revs.first() == Some(&edge.to))
+    });
+    update.plans = plans;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::update::Variants;
+
+    fn edge(from: &str, to: &str, size: u64) -> Edge {
+        let artifact = Artifact {
+            name: format!("{to}-{from}-update.zip"),
+            size,
+            ..Default::default()
+        };
+        Edge {
+            from: from.into(),
+            to: to.into(),
+            variants: Variants::from([(PLAN_VARIANT.to_string(), artifact)]),
+        }
+    }
+
+    #[test]
+    fn test_plans() {
+        let graph = [
+            edge("a", "b", 10),
+            edge("b", "c", 10),
+            edge("a", "d", 100),
+            edge("b", "d", 50),
+            edge("c", "d", 20),
+            edge("x", "y", 1),
+        ];
+        let plans = plans(&graph, "d", PLAN_VARIANT);
+        assert_eq!(
+            plans["a"],
+            Plan {
+                revs: vec!["b".into(), "c".into(), "d".into()],
+                size: 40
+            }
+        );
+        assert_eq!(plans["b"].revs, ["c", "d"]);
+        assert_eq!(plans["c"].size, 20);
+        assert!(!plans.contains_key("d"));
+        assert!(!plans.contains_key("x"));
+    }
+
+    #[test]
+    fn test_update_graph() {
+        let mut update = Update {
+            latest: Some("d".into()),
+            graph: vec![edge("a", "b", 10), edge("b", "c", 10), edge("x", "y", 1)],
+            ..Default::default()
+        };
+        update.details.insert(
+            "c".into(),
+            crate::update::UpdateInfo {
+                variants: edge("c", "d", 5).variants,
+                ..Default::default()
+            },
+        );
+        update_graph(&mut update, 3);
+        assert_eq!(update.graph.len(), 3);
+        assert_eq!(update.plans["a"].size, 25);
+
+        update_graph(&mut update, 2);
+        assert!(!update.plans.contains_key("a"));
+        assert_eq!(update.graph.len(), 2);
+    }
+
+    #[test]
+    fn test_prune_graph() {
+        // Every release has update zips from the last three revs.
+        let mut update = Update::default();
+        for release in 1..50u64 {
+            let latest = format!("r{release}");
+            let mut graph = update.graph.clone();
+            add_edges(&mut graph, super::edges(&update));
+            update = Update {
+                latest: Some(latest.clone()),
+                graph,
+                ..Default::default()
+            };
+            for old in release.saturating_sub(3)..release {
+                update.details.insert(
+                    format!("r{old}"),
+                    crate::update::UpdateInfo {
+                        variants: edge(&format!("r{old}"), &latest, 10).variants,
+                        ..Default::default()
+                    },
+                );
+            }
+            update_graph(&mut update, 4);
+        }
+        // Four hops of up to three revs each, and one edge per planned rev.
+        assert_eq!(update.plans.len(), 12);
+        assert_eq!(update.graph.len(), 12);
+        assert_eq!(update.plans["r37"].revs, ["r40", "r43", "r46", "r49"]);
+        assert!(!update.plans.contains_key("r36"));
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::hash::hash_file;
+
+/// How the artifacts of a rev were built, written as `<rev>-provenance.json`.
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct Provenance {
+    pub rev: String,
+    pub differy_version: String,
+    /// Commits of the checkouts the build came from, e.g. `content` or `yari`.
+    #[serde(default)]
+    pub commits: BTreeMap<String, String>,
+    pub args: Vec<String>,
+    /// Fingerprint of the link rewrites of the zip variants.
+    pub rewrite_rules: String,
+    /// SHA-256 of the checksums of the input tree.
+    pub tree: String,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    /// SHA-256 of every artifact by name.
+    pub artifacts: BTreeMap<String, String>,
+}
+
+/// SHA-256 of `hashes` as listed in a checksum archive, i.e. of the
+/// checksum list in `<rev>-checksums.zip`.
+pub(crate) fn tree_hash<T: AsRef<str>>(hashes: &[(T, T)]) -> String {
+    let mut hasher = Sha256::new();
+    for (hash, file) in hashes {
+        hasher.update(format!("{} {}\n", hash.as_ref(), file.as_ref()));
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+impl Provenance {
+    pub fn file_name(rev: &str) -> String {
+        format!("{rev}-provenance.json")
+    }
+
+    pub fn from_file(path: &Path) -> std::io::Result<Self> {
+        let file = File::open(path)
+            .map_err(|e| std::io::Error::other(format!("{}: {e}", path.display())))?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    /// Record the SHA-256 of the artifacts `names` in `dir`.
+    pub fn add_artifacts<T: AsRef<str>>(&mut self, dir: &Path, names: &[T]) -> std::io::Result<()> {
+        for name in names {
+            let name = name.as_ref();
+            self.artifacts
+                .insert(name.to_string(), hash_file(dir.join(name))?);
+        }
+        Ok(())
+    }
+
+    /// Check the artifacts found in `dir` against their SHA-256. Returns the
+    /// missing ones.
+    pub fn verify(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+        let mut missing = vec![];
+        for (name, sha256) in &self.artifacts {
+            let path = dir.join(name);
+            match hash_file(&path) {
+                Ok(actual) if actual == *sha256 => {}
+                Ok(_) => {
+                    return Err(std::io::Error::other(format!(
+                        "{}: SHA-256 mismatch",
+                        path.display()
+                    )))
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => missing.push(path),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(missing)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_provenance() {
+        let dir = TempDir::new("provenance");
+        std::fs::write(dir.join("b-content.zip"), "zip").unwrap();
+        std::fs::write(dir.join("b-a-update.zip"), "update").unwrap();
+        let mut provenance = Provenance {
+            rev: "b".into(),
+            differy_version: "0.0.0".into(),
+            commits: BTreeMap::new(),
+            args: vec![],
+            rewrite_rules: String::new(),
+            tree: tree_hash(&[("00ff", "en-us/index.json")]),
+            started: Utc::now(),
+            finished: Utc::now(),
+            artifacts: BTreeMap::new(),
+        };
+        provenance
+            .add_artifacts(&dir, &["b-content.zip", "b-a-update.zip"])
+            .unwrap();
+        let path = dir.join(Provenance::file_name("b"));
+        provenance.save(&path).unwrap();
+        let provenance = Provenance::from_file(&path).unwrap();
+        assert!(provenance.verify(&dir).unwrap().is_empty());
+
+        std::fs::remove_file(dir.join("b-a-update.zip")).unwrap();
+        assert_eq!(
+            provenance.verify(&dir).unwrap(),
+            [dir.join("b-a-update.zip")]
+        );
+        std::fs::write(dir.join("b-content.zip"), "tampered").unwrap();
+        let err = provenance.verify(&dir).unwrap_err();
+        assert!(err.to_string().contains("SHA-256 mismatch"), "{err}");
+    }
+}
@@ -0,0 +1,301 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::OsRng;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::update::{Artifact, Signature, Update};
+
+/// A secret key to sign with, read from a file holding its 32 bytes in hex.
+pub(crate) struct Key {
+    id: String,
+    key: SigningKey,
+}
+
+/// The public keys to verify with by key ID.
+pub(crate) struct PublicKeys {
+    keys: HashMap<String, VerifyingKey>,
+}
+
+/// Detached signatures of a file, stored next to it as
+/// `<file>.<sha256>.sig` (e.g. `update.json.<sha256>.sig`), see `signature_path`.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub(crate) struct Signatures {
+    pub signatures: Vec<Signature>,
+}
+
+/// The ID of `key`, the start of the SHA-256 of its bytes. Signatures record
+/// it, so keys can be rotated.
+fn key_id(key: &VerifyingKey) -> String {
+    hex::encode(&Sha256::digest(key.as_bytes())[..8])
+}
+
+fn read_key(path: &Path) -> std::io::Result<[u8; 32]> {
+    let error =
+        |e: &dyn std::fmt::Display| std::io::Error::other(format!("{}: {e}", path.display()));
+    let key = fs::read_to_string(path).map_err(|e| error(&e))?;
+    let key = hex::decode(key.trim()).map_err(|e| error(&e))?;
+    key.try_into().map_err(|_| error(&"not a 32 byte key"))
+}
+
+/// Path of the public key of the secret key at `path`.
+pub(crate) fn public_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{file_name}.pub"))
+}
+
+/// Path of the detached signatures of the file at `path` with `content`,
+/// e.g. `update.json.<sha256>.sig`. It is named after the SHA-256 of the
+/// content, so a new signature never gets paired with an old file while
+/// both are being replaced.
+fn signature_path(path: &Path, content: &[u8]) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{file_name}.{:x}.sig", Sha256::digest(content)))
+}
+
+/// Write a new secret key to `path` and its public key next to it. Returns
+/// the key ID.
+pub(crate) fn generate(path: &Path) -> std::io::Result<String> {
+    let key = SigningKey::generate(&mut OsRng);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .map_err(|e| std::io::Error::other(format!("{}: {e}", path.display())))?;
+    writeln!(file, "{}", hex::encode(key.to_bytes()))?;
+    let public = key.verifying_key();
+    fs::write(
+        public_path(path),
+        format!("{}\n", hex::encode(public.as_bytes())),
+    )?;
+    Ok(key_id(&public))
+}
+
+impl Key {
+    pub fn from_file(path: &Path) -> std::io::Result<Self> {
+        let key = SigningKey::from_bytes(&read_key(path)?);
+        Ok(Self {
+            id: key_id(&key.verifying_key()),
+            key,
+        })
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        Signature {
+            key_id: self.id.clone(),
+            signature: hex::encode(self.key.sign(message).to_bytes()),
+        }
+    }
+}
+
+impl PublicKeys {
+    pub fn from_files<P: AsRef<Path>>(paths: &[P]) -> std::io::Result<Self> {
+        let mut keys = HashMap::new();
+        for path in paths {
+            let path = path.as_ref();
+            let key = VerifyingKey::from_bytes(&read_key(path)?)
+                .map_err(|e| std::io::Error::other(format!("{}: {e}", path.display())))?;
+            keys.insert(key_id(&key), key);
+        }
+        Ok(Self { keys })
+    }
+
+    /// Check that `message` is signed by at least one of the keys. Signatures
+    /// by unknown keys, e.g. retired ones, are ignored, but any bad signature
+    /// by a known key fails.
+    pub fn verify(&self, message: &[u8], signatures: &[Signature]) -> std::io::Result<()> {
+        let mut verified = false;
+        for signature in signatures {
+            let Some(key) = self.keys.get(&signature.key_id) else {
+                continue;
+            };
+            let bad =
+                || std::io::Error::other(format!("bad signature by key {}", signature.key_id));
+            let bytes = hex::decode(&signature.signature).map_err(|_| bad())?;
+            let parsed = ed25519_dalek::Signature::from_slice(&bytes).map_err(|_| bad())?;
+            key.verify(message, &parsed).map_err(|_| bad())?;
+            verified = true;
+        }
+        match (verified, signatures.is_empty()) {
+            (true, _) => Ok(()),
+            (false, true) => Err(std::io::Error::other("not signed")),
+            (false, false) => Err(std::io::Error::other("not signed by a known key")),
+        }
+    }
+}
+
+/// What the signature of an artifact covers.
+fn message(artifact: &Artifact) -> String {
+    format!("{} {}", artifact.name, artifact.sha256)
+}
+
+fn artifacts_mut(update: &mut Update) -> Vec<&mut Artifact> {
+    let mut artifacts = vec![];
+    if let Some(content) = &mut update.content {
+        artifacts.extend(content.variants.values_mut());
+        artifacts.extend(content.bundles.values_mut().flat_map(|v| v.values_mut()));
+    }
+    for info in update.details.values_mut() {
+        artifacts.extend(info.variants.values_mut());
+        artifacts.extend(info.bundles.values_mut().flat_map(|v| v.values_mut()));
+    }
+    for edge in &mut update.graph {
+        artifacts.extend(edge.variants.values_mut());
+    }
+    artifacts
+}
+
+/// All artifacts of `update` and its channels, including parts.
+pub(crate) fn artifacts(update: &Update) -> Vec<&Artifact> {
+    let mut artifacts: Vec<&Artifact> = vec![];
+    if let Some(content) = &update.content {
+        artifacts.extend(content.variants.values());
+        artifacts.extend(content.bundles.values().flat_map(|v| v.values()));
+    }
+    for info in update.details.values() {
+        artifacts.extend(info.variants.values());
+        artifacts.extend(info.bundles.values().flat_map(|v| v.values()));
+    }
+    for edge in &update.graph {
+        artifacts.extend(edge.variants.values());
+    }
+    let parts = artifacts
+        .iter()
+        .flat_map(|artifact| artifact.parts.iter())
+        .collect::<Vec<_>>();
+    artifacts.extend(parts);
+    for channel in update.channels.values() {
+        artifacts.extend(self::artifacts(channel));
+    }
+    artifacts
+}
+
+fn sign_artifact(artifact: &mut Artifact, keys: &[Key]) {
+    let message = message(artifact);
+    artifact.signatures = keys
+        .iter()
+        .map(|key| key.sign(message.as_bytes()))
+        .collect();
+    for part in &mut artifact.parts {
+        sign_artifact(part, keys);
+    }
+}
+
+/// Sign all artifacts of `update` (but not of its channels) with `keys`,
+/// replacing older signatures.
+pub(crate) fn sign_artifacts(update: &mut Update, keys: &[Key]) {
+    for artifact in artifacts_mut(update) {
+        sign_artifact(artifact, keys);
+    }
+}
+
+/// Check the signature of `artifact`.
+pub(crate) fn verify_artifact(artifact: &Artifact, keys: &PublicKeys) -> std::io::Result<()> {
+    keys.verify(message(artifact).as_bytes(), &artifact.signatures)
+        .map_err(|e| std::io::Error::other(format!("{}: {e}", artifact.name)))
+}
+
+/// Write the signatures of the file at `path` next to it, see
+/// `signature_path`.
+pub(crate) fn sign_file(path: &Path, keys: &[Key]) -> std::io::Result<PathBuf> {
+    let content = fs::read(path)?;
+    let signatures = Signatures {
+        signatures: keys.iter().map(|key| key.sign(&content)).collect(),
+    };
+    let out = signature_path(path, &content);
+    fs::write(&out, serde_json::to_vec_pretty(&signatures)?)?;
+    Ok(out)
+}
+
+/// Check the file at `path` against the signatures of its content.
+pub(crate) fn verify_file(path: &Path, keys: &PublicKeys) -> std::io::Result<()> {
+    let content = fs::read(path)?;
+    let sig_path = signature_path(path, &content);
+    let signatures: Signatures = match fs::read(&sig_path) {
+        Ok(signatures) => serde_json::from_slice(&signatures)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(std::io::Error::other(format!(
+                "{}: not signed, there is no {}",
+                path.display(),
+                sig_path.display()
+            )));
+        }
+        Err(e) => {
+            return Err(std::io::Error::other(format!(
+                "{}: {e}",
+                sig_path.display()
+            )))
+        }
+    };
+    keys.verify(&content, &signatures.signatures)
+        .map_err(|e| std::io::Error::other(format!("{}: {e}", path.display())))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+    use crate::update::{ContentInfo, Variants};
+
+    #[test]
+    fn test_sign_and_verify() {
+        let dir = TempDir::new("sign");
+        let old_id = generate(&dir.join("old.key")).unwrap();
+        let new_id = generate(&dir.join("new.key")).unwrap();
+        assert!(generate(&dir.join("new.key")).is_err());
+        let old = Key::from_file(&dir.join("old.key")).unwrap();
+        let new = Key::from_file(&dir.join("new.key")).unwrap();
+        assert_eq!((old.id.as_str(), new.id.as_str()), (&*old_id, &*new_id));
+
+        let artifact = Artifact {
+            name: "abc-content.zip".into(),
+            sha256: "00ff".into(),
+            ..Default::default()
+        };
+        let mut update = Update {
+            content: Some(ContentInfo {
+                variants: Variants::from([("web".to_string(), artifact)]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        // During a rotation both keys sign.
+        sign_artifacts(&mut update, &[old, new]);
+        let keys = |names: &[&str]| {
+            let paths = names.iter().map(|name| dir.join(name)).collect::<Vec<_>>();
+            PublicKeys::from_files(&paths).unwrap()
+        };
+        let signed = artifacts(&update)[0].clone();
+        assert_eq!(signed.signatures.len(), 2);
+        verify_artifact(&signed, &keys(&["new.key.pub"])).unwrap();
+        verify_artifact(&signed, &keys(&["old.key.pub"])).unwrap();
+
+        let mut tampered = signed.clone();
+        tampered.sha256 = "ff00".into();
+        assert!(verify_artifact(&tampered, &keys(&["new.key.pub"])).is_err());
+        let other = dir.join("other.key");
+        generate(&other).unwrap();
+        let err = verify_artifact(&signed, &keys(&["other.key.pub"])).unwrap_err();
+        assert!(err.to_string().contains("not signed by a known key"));
+
+        let file = dir.join("update.json");
+        fs::write(&file, "{}").unwrap();
+        sign_file(&file, &[Key::from_file(&dir.join("new.key")).unwrap()]).unwrap();
+        verify_file(&file, &keys(&["new.key.pub"])).unwrap();
+        // The signatures of the old content don't apply to the new one.
+        fs::write(&file, "{ }").unwrap();
+        let err = verify_file(&file, &keys(&["new.key.pub"])).unwrap_err();
+        assert!(err.to_string().contains("not signed"), "{err}");
+        let sig = sign_file(&file, &[Key::from_file(&dir.join("new.key")).unwrap()]).unwrap();
+        fs::write(sig, r#"{"signatures": []}"#).unwrap();
+        assert!(verify_file(&file, &keys(&["new.key.pub"])).is_err());
+    }
+}
@@ -0,0 +1,281 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::diff::{diff, Diff};
+use crate::package::APP_PREFIX;
+
+/// Bundle for all files not matched by any other bundle.
+pub(crate) const SHARED_BUNDLE: &str = "shared";
+
+/// A named bundle and the globs of the files it contains, as read from
+/// `--components` and recorded in `update.json`.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub(crate) struct BundleDef {
+    pub name: String,
+    pub files: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Bundle {
+    def: BundleDef,
+    globs: GlobSet,
+}
+
+/// Partitions the tree into named bundles that are packaged on their own, so
+/// clients only download what they need. A file belongs to the first bundle
+/// matching it.
+#[derive(Debug, Default)]
+pub(crate) struct Split {
+    bundles: Vec<Bundle>,
+    rest: Option<String>,
+}
+
+impl Bundle {
+    fn new(def: BundleDef) -> std::io::Result<Self> {
+        check_name(&def.name)?;
+        let mut builder = GlobSetBuilder::new();
+        for glob in &def.files {
+            builder.add(Glob::new(glob).map_err(std::io::Error::other)?);
+        }
+        let globs = builder.build().map_err(std::io::Error::other)?;
+        Ok(Self { def, globs })
+    }
+}
+
+/// Bundle names end up in file names like `<rev>-<bundle>-content.zip`, so
+/// they must not leave `--out` or be mistaken for the shared bundle or the
+/// app variant (`<rev>-app-content.zip`).
+fn check_name(name: &str) -> std::io::Result<()> {
+    let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_';
+    if name.is_empty() || !name.chars().all(valid) {
+        return Err(std::io::Error::other(format!(
+            "{name:?} is not a valid bundle name, use only a-z, 0-9, - and _"
+        )));
+    }
+    if name == SHARED_BUNDLE || name.split('-').any(|part| part == APP_PREFIX) {
+        return Err(std::io::Error::other(format!(
+            "{name} is reserved and can't name a bundle"
+        )));
+    }
+    Ok(())
+}
+
+impl Split {
+    fn new(bundles: Vec<Bundle>, rest: Option<String>) -> std::io::Result<Self> {
+        for (i, bundle) in bundles.iter().enumerate() {
+            if bundles[..i].iter().any(|b| b.def.name == bundle.def.name) {
+                return Err(std::io::Error::other(format!(
+                    "bundle {} is defined twice",
+                    bundle.def.name
+                )));
+            }
+        }
+        Ok(Self { bundles, rest })
+    }
+
+    /// One bundle per locale (`en-us/…`, `fr/…`) plus a shared bundle for
+    /// everything else (assets, examples, …).
+    pub fn locales<T: AsRef<str>>(locales: &[T]) -> std::io::Result<Self> {
+        if locales.is_empty() {
+            return Ok(Self::default());
+        }
+        let mut bundles = vec![];
+        for locale in locales {
+            let locale = locale.as_ref().to_lowercase();
+            bundles.push(Bundle::new(BundleDef {
+                files: vec![format!("{locale}/**")],
+                name: locale,
+            })?);
+        }
+        Self::new(bundles, Some(SHARED_BUNDLE.to_string()))
+    }
+
+    /// Components as defined in a JSON file:
+    ///
+    /// ```json
+    /// [
+    ///   { "name": "bcd", "files": ["bcd/**"] },
+    ///   { "name": "examples", "files": ["examples/**"] },
+    ///   { "name": "docs", "files": ["**"] }
+    /// ]
+    /// ```
+    ///
+    /// Files matching no component are in none of the component bundles.
+    pub fn components(path: &Path) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+        let defs: Vec<BundleDef> = serde_json::from_reader(reader)?;
+        Self::from_defs(defs)
+    }
+
+    pub fn from_defs(defs: Vec<BundleDef>) -> std::io::Result<Self> {
+        let bundles = defs
+            .into_iter()
+            .map(Bundle::new)
+            .collect::<Result<_, _>>()?;
+        Self::new(bundles, None)
+    }
+
+    /// Fail if a bundle of `other` has the name of one of these, e.g. a
+    /// component named like a locale.
+    pub fn check_disjoint(&self, other: &Split) -> std::io::Result<()> {
+        let names = self.names();
+        match other.names().into_iter().find(|name| names.contains(name)) {
+            Some(name) => Err(std::io::Error::other(format!(
+                "bundle {name} is defined twice"
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bundles.is_empty()
+    }
+
+    /// Definitions of the bundles matched by globs, i.e. without the shared one.
+    pub fn defs(&self) -> Vec<BundleDef> {
+        self.bundles.iter().map(|b| b.def.clone()).collect()
+    }
+
+    /// Names of the bundles matched by globs, i.e. without the shared one.
+    pub fn bundle_names(&self) -> Vec<String> {
+        self.bundles.iter().map(|b| b.def.name.clone()).collect()
+    }
+
+    /// Names of all bundles.
+    pub fn names(&self) -> Vec<String> {
+        self.bundles
+            .iter()
+            .map(|b| &b.def.name)
+            .chain(self.rest.iter())
+            .cloned()
+            .collect()
+    }
+
+    pub fn bundle_of(&self, file: &str) -> Option<&str> {
+        self.bundles
+            .iter()
+            .find(|b| b.globs.is_match(file))
+            .map(|b| b.def.name.as_str())
+            .or(self.rest.as_deref())
+    }
+
+    fn filter<'a, T: AsRef<str>>(
+        &self,
+        name: &str,
+        hashes: &'a [(T, T)],
+    ) -> Vec<(&'a str, &'a str)> {
+        hashes
+            .iter()
+            .filter(|(_, file)| self.bundle_of(file.as_ref()) == Some(name))
+            .map(|(hash, file)| (hash.as_ref(), file.as_ref()))
+            .collect()
+    }
+
+    /// The hashes of every bundle.
+    pub fn split_hashes<'a, T: AsRef<str>>(
+        &self,
+        hashes: &'a [(T, T)],
+    ) -> Vec<(String, Vec<(&'a str, &'a str)>)> {
+        self.names()
+            .into_iter()
+            .map(|name| {
+                let hashes = self.filter(&name, hashes);
+                (name, hashes)
+            })
+            .collect()
+    }
+
+    /// The diff of every bundle, from the old and new hashes of its files.
+    pub fn split_diff<T: AsRef<str>, S: AsRef<str>>(
+        &self,
+        old: &[(T, T)],
+        new: &[(S, S)],
+    ) -> std::io::Result<Vec<(String, Diff)>> {
+        self.names()
+            .into_iter()
+            .map(|name| {
+                let diff = diff(&self.filter(&name, old), &self.filter(&name, new))?;
+                Ok((name, diff))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_locales() {
+        let split = Split::locales(&["en-US", "fr"]).unwrap();
+        assert_eq!(split.names(), ["en-us", "fr", "shared"]);
+        assert_eq!(split.bundle_names(), ["en-us", "fr"]);
+        assert!(Split::locales::<&str>(&[]).unwrap().names().is_empty());
+        assert_eq!(split.bundle_of("en-us/docs/web/index.json"), Some("en-us"));
+        assert_eq!(split.bundle_of("fr/docs/web/index.json"), Some("fr"));
+        assert_eq!(split.bundle_of("static/js/main.js"), Some("shared"));
+        assert_eq!(split.bundle_of("french/index.json"), Some("shared"));
+
+        let hashes = [("a", "en-us/a"), ("b", "fr/b"), ("c", "static/c")];
+        let split_hashes = split.split_hashes(&hashes);
+        assert_eq!(
+            split_hashes[0],
+            ("en-us".to_string(), vec![("a", "en-us/a")])
+        );
+        assert_eq!(
+            split_hashes[2],
+            ("shared".to_string(), vec![("c", "static/c")])
+        );
+    }
+
+    #[test]
+    fn test_split_components() {
+        let split = Split::from_defs(
+            serde_json::from_str(
+                r#"[
+                    {"name": "bcd", "files": ["bcd/**"]},
+                    {"name": "docs", "files": ["*/docs/**"]}
+                ]"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(split.names(), ["bcd", "docs"]);
+        assert_eq!(split.bundle_of("bcd/api/fetch.json"), Some("bcd"));
+        assert_eq!(split.bundle_of("en-us/docs/web/index.json"), Some("docs"));
+        assert_eq!(split.bundle_of("static/js/main.js"), None);
+
+        let old = [("a", "bcd/a"), ("b", "en-us/docs/b"), ("c", "static/c")];
+        let new = [("a", "bcd/a"), ("x", "en-us/docs/b")];
+        let diffs = split.split_diff(&old, &new).unwrap();
+        assert_eq!(diffs[0].0, "bcd");
+        assert!(diffs[0].1.update_iter().next().is_none());
+        assert_eq!(diffs[1].0, "docs");
+        assert_eq!(diffs[1].1.modified, ["en-us/docs/b"]);
+        assert!(diffs[1].1.removed.is_empty());
+    }
+
+    #[test]
+    fn test_bundle_names() {
+        let def = |name: &str| BundleDef {
+            name: name.to_string(),
+            files: vec!["**".to_string()],
+        };
+        for name in ["app", "my-app", "shared", "../x", "a/b", "Docs", ""] {
+            assert!(Split::from_defs(vec![def(name)]).is_err(), "{name}");
+        }
+        assert!(Split::from_defs(vec![def("web-apps"), def("bcd_2")]).is_ok());
+        let err = Split::from_defs(vec![def("bcd"), def("bcd")]).unwrap_err();
+        assert!(err.to_string().contains("defined twice"), "{err}");
+        assert!(Split::locales(&["en-US", "en-us"]).is_err());
+
+        let locales = Split::locales(&["fr"]).unwrap();
+        let components = Split::from_defs(vec![def("bcd")]).unwrap();
+        assert!(locales.check_disjoint(&components).is_ok());
+        let components = Split::from_defs(vec![def("fr")]).unwrap();
+        assert!(locales.check_disjoint(&components).is_err());
+    }
+}
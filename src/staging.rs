@@ -0,0 +1,270 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::OpenOptions,
+    io::{ErrorKind, Write},
+};
+
+use async_std::path::{Path, PathBuf};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::hash::hash_file;
+
+const LOCK_FILENAME: &str = ".differy.lock";
+const JOURNAL_FILENAME: &str = ".journal.json";
+
+/// Exclusive use of an output directory, released when dropped.
+pub(crate) struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    /// Lock `dir`, failing if another run holds the lock.
+    pub fn acquire(dir: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(LOCK_FILENAME);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                writeln!(file, "{}", std::process::id())?;
+                Ok(Self { path })
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let pid = std::fs::read_to_string(&path).unwrap_or_default();
+                Err(std::io::Error::other(format!(
+                    "{} is locked by process {}, remove {} if that run is gone",
+                    dir.display(),
+                    pid.trim(),
+                    path.display()
+                )))
+            }
+            Err(e) => Err(std::io::Error::other(format!("{}: {e}", path.display()))),
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A completed step of a run: the files it wrote with their SHA-256 and its
+/// result.
+#[derive(Deserialize, Serialize, Debug)]
+struct Step {
+    input: String,
+    files: BTreeMap<String, String>,
+    result: serde_json::Value,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct Journal {
+    steps: BTreeMap<String, Step>,
+}
+
+/// A directory next to the artifacts in `out` to write the artifacts of `rev`
+/// to, so a failed run leaves `out` untouched. Completed steps are recorded,
+/// so a later run can resume where a failed one stopped.
+pub(crate) struct Staging {
+    dir: PathBuf,
+    out: PathBuf,
+    journal: Journal,
+    used: BTreeSet<String>,
+}
+
+/// A fingerprint of the inputs of a step.
+pub(crate) fn fingerprint(inputs: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for input in inputs {
+        hasher.update(Sha256::digest(input));
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+impl Staging {
+    /// The staging directory for `rev` in `out`. Steps of a failed run are
+    /// kept if their files are intact, everything else is removed.
+    pub fn new(out: &Path, rev: &str) -> std::io::Result<Self> {
+        let dir = out.join(format!(".staging-{rev}"));
+        std::fs::create_dir_all(&dir)?;
+        let mut journal: Journal = std::fs::read(dir.join(JOURNAL_FILENAME))
+            .ok()
+            .and_then(|journal| serde_json::from_slice(&journal).ok())
+            .unwrap_or_default();
+        journal.steps.retain(|_, step| {
+            step.files.iter().all(|(name, sha256)| {
+                hash_file(dir.join(name)).is_ok_and(|actual| actual == *sha256)
+            })
+        });
+        let staging = Self {
+            dir,
+            out: out.to_path_buf(),
+            journal,
+            used: BTreeSet::new(),
+        };
+        let kept = staging.journal_files(|_| true);
+        for name in staging.files()?.difference(&kept) {
+            std::fs::remove_file(staging.dir.join(name))?;
+        }
+        staging.save_journal()?;
+        Ok(staging)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Names of the staged files.
+    pub fn files(&self) -> std::io::Result<BTreeSet<String>> {
+        let mut files = BTreeSet::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if !name.starts_with('.') {
+                files.insert(name);
+            }
+        }
+        Ok(files)
+    }
+
+    fn journal_files(&self, filter: impl Fn(&str) -> bool) -> BTreeSet<String> {
+        self.journal
+            .steps
+            .iter()
+            .filter(|(key, _)| filter(key))
+            .flat_map(|(_, step)| step.files.keys().cloned())
+            .collect()
+    }
+
+    fn save_journal(&self) -> std::io::Result<()> {
+        let tmp = self.dir.join(format!("{JOURNAL_FILENAME}.tmp"));
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&self.journal)?)?;
+        std::fs::rename(tmp, self.dir.join(JOURNAL_FILENAME))
+    }
+
+    /// The result of `step` if a previous run completed it with the same
+    /// `input`.
+    pub fn resume<T: DeserializeOwned>(
+        &mut self,
+        step: &str,
+        input: &str,
+    ) -> std::io::Result<Option<T>> {
+        match self.journal.steps.get(step) {
+            Some(done) if done.input == input => {
+                self.used.insert(step.to_string());
+                Ok(Some(serde_json::from_value(done.result.clone())?))
+            }
+            Some(_) => {
+                let stale = self.journal.steps.remove(step).unwrap();
+                for name in stale.files.keys() {
+                    std::fs::remove_file(self.dir.join(name))?;
+                }
+                self.save_journal()?;
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Record that `step` completed with `result`, having written all files
+    /// staged since `before`.
+    pub fn record<T: Serialize>(
+        &mut self,
+        step: &str,
+        input: &str,
+        before: &BTreeSet<String>,
+        result: &T,
+    ) -> std::io::Result<()> {
+        let mut files = BTreeMap::new();
+        for name in self.files()?.difference(before) {
+            files.insert(name.clone(), hash_file(self.dir.join(name))?);
+        }
+        self.journal.steps.insert(
+            step.to_string(),
+            Step {
+                input: input.to_string(),
+                files,
+                result: serde_json::to_value(result)?,
+            },
+        );
+        self.used.insert(step.to_string());
+        self.save_journal()
+    }
+
+    /// Move all staged files to `out`, dropping those of steps this run didn't
+    /// need. Every file is renamed atomically, so `out` never contains partial
+    /// files.
+    pub fn commit(self) -> std::io::Result<()> {
+        for name in self.journal_files(|step| !self.used.contains(step)) {
+            std::fs::remove_file(self.dir.join(name))?;
+        }
+        for name in self.files()? {
+            std::fs::rename(self.dir.join(&name), self.out.join(&name))?;
+        }
+        std::fs::remove_dir_all(&self.dir)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_staging() {
+        let dir = TempDir::new("staging");
+        let out = Path::new(&*dir);
+        let lock = Lock::acquire(out).unwrap();
+        assert!(Lock::acquire(out).is_err());
+
+        let staging = Staging::new(out, "abc").unwrap();
+        std::fs::write(staging.dir().join("abc-content.zip"), "zip").unwrap();
+        assert!(!std::fs::exists(out.join("abc-content.zip")).unwrap());
+        staging.commit().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(out.join("abc-content.zip")).unwrap(),
+            "zip"
+        );
+        assert!(!std::fs::exists(out.join(".staging-abc")).unwrap());
+
+        drop(lock);
+        drop(Lock::acquire(out).unwrap());
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir = TempDir::new("resume");
+        let out = Path::new(&*dir);
+        let mut staging = Staging::new(out, "c").unwrap();
+        for (step, file) in [("a", "c-a-update.zip"), ("b", "c-b-update.zip")] {
+            let before = staging.files().unwrap();
+            std::fs::write(staging.dir().join(file), step).unwrap();
+            staging.record(step, "in", &before, &step).unwrap();
+        }
+        // A partial file of a step that didn't complete.
+        std::fs::write(staging.dir().join("c-x-update.zip"), "x").unwrap();
+        drop(staging);
+        std::fs::write(out.join(".staging-c/c-b-update.zip"), "corrupt").unwrap();
+
+        let mut staging = Staging::new(out, "c").unwrap();
+        assert_eq!(
+            staging.files().unwrap(),
+            BTreeSet::from(["c-a-update.zip".to_string()])
+        );
+        assert_eq!(staging.resume::<String>("b", "in").unwrap(), None);
+        assert_eq!(staging.resume::<String>("a", "other").unwrap(), None);
+        assert!(staging.files().unwrap().is_empty());
+
+        let before = staging.files().unwrap();
+        std::fs::write(staging.dir().join("c-a-update.zip"), "a").unwrap();
+        staging.record("a", "in", &before, &"a").unwrap();
+        drop(staging);
+        let mut staging = Staging::new(out, "c").unwrap();
+        assert_eq!(
+            staging.resume::<String>("a", "in").unwrap().as_deref(),
+            Some("a")
+        );
+        staging.commit().unwrap();
+        assert!(std::fs::exists(out.join("c-a-update.zip")).unwrap());
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    fs::File,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use zip::result::ZipError;
+
+use crate::compress::unzip_content;
+
+/// Where the `<rev>-checksums.zip` and other artifacts of earlier revs are
+/// read from.
+pub(crate) trait ChecksumStore {
+    /// Local path of the artifact `file_name`.
+    fn fetch(&self, file_name: &str) -> std::io::Result<PathBuf>;
+
+    /// The checksums of `rev` as written by `package_hashes`.
+    fn checksums(&self, rev: &str) -> std::io::Result<String> {
+        read_checksum_zip(&self.fetch(&file_name(rev))?)
+    }
+}
+
+/// Checksum zips in a local directory.
+pub(crate) struct LocalStore {
+    dir: PathBuf,
+}
+
+/// Checksum zips below an HTTP(S) base URL, cached in a local directory.
+pub(crate) struct HttpStore {
+    base_url: String,
+    cache: PathBuf,
+}
+
+fn file_name(rev: &str) -> String {
+    format!("{rev}-checksums.zip")
+}
+
+/// The checksum store at `location`, either a directory or an HTTP(S) base
+/// URL. Downloads are cached in `cache`.
+pub(crate) fn open(location: &str, cache: &Path) -> Box<dyn ChecksumStore> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        Box::new(HttpStore::new(location, cache))
+    } else {
+        Box::new(LocalStore::new(location))
+    }
+}
+
+impl LocalStore {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl ChecksumStore for LocalStore {
+    fn fetch(&self, file_name: &str) -> std::io::Result<PathBuf> {
+        let path = self.dir.join(file_name);
+        if path.exists() {
+            Ok(path)
+        } else {
+            Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("{}: not found", path.display()),
+            ))
+        }
+    }
+}
+
+impl HttpStore {
+    pub fn new(base_url: &str, cache: &Path) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            cache: cache.to_path_buf(),
+        }
+    }
+
+    fn download(&self, url: &str, path: &Path) -> std::io::Result<()> {
+        let response = ureq::get(url).call().map_err(|e| match e {
+            ureq::Error::StatusCode(404) => {
+                std::io::Error::new(ErrorKind::NotFound, format!("{url}: not found"))
+            }
+            e => std::io::Error::other(format!("{url}: {e}")),
+        })?;
+        // Only complete downloads end up in the cache.
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = path.with_file_name(format!("{file_name}.tmp"));
+        let mut file = File::create(&tmp)?;
+        std::io::copy(&mut response.into_body().into_reader(), &mut file)?;
+        file.sync_all()?;
+        std::fs::rename(tmp, path)
+    }
+}
+
+impl ChecksumStore for HttpStore {
+    fn fetch(&self, file_name: &str) -> std::io::Result<PathBuf> {
+        let path = self.cache.join(file_name);
+        if !path.exists() {
+            let url = format!("{}/{file_name}", self.base_url);
+            // Not on stdout, which may carry JSON, see `package --dry-run`.
+            eprintln!("downloading {url}");
+            std::fs::create_dir_all(&self.cache)?;
+            self.download(&url, &path)?;
+        }
+        Ok(path)
+    }
+}
+
+/// The checksums in `zip_file`, a `<rev>-checksums.zip`. A missing archive
+/// or checksum list is `ErrorKind::NotFound`, unlike a corrupt archive.
+pub(crate) fn read_checksum_zip(zip_file: &Path) -> std::io::Result<String> {
+    let file_name = zip_file
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    unzip_content(zip_file.into(), &file_name).map_err(|e| {
+        let kind = match &e {
+            ZipError::Io(e) => e.kind(),
+            ZipError::FileNotFound => ErrorKind::NotFound,
+            _ => ErrorKind::Other,
+        };
+        std::io::Error::new(kind, format!("{}: {e}", zip_file.display()))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::compress::zip_content;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_http_store() {
+        let dir = TempDir::new("store");
+        let served = dir.join("served");
+        std::fs::create_dir_all(&served).unwrap();
+        let zip = served.join("abc-checksums.zip");
+        zip_content("abc-checksums", b"123 a.txt\n", zip.as_path().into()).unwrap();
+        let zip = std::fs::read(zip).unwrap();
+
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}/packages", server.server_addr());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let response = if request.url() == "/packages/abc-checksums.zip" {
+                    tiny_http::Response::from_data(zip.clone())
+                } else {
+                    tiny_http::Response::from_data(vec![]).with_status_code(404)
+                };
+                request.respond(response).unwrap();
+            }
+        });
+
+        let cache = dir.join("cache");
+        let store = open(&base_url, &cache);
+        assert_eq!(store.checksums("abc").unwrap(), "123 a.txt\n");
+        assert_eq!(store.checksums("abc").unwrap(), "123 a.txt\n");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(cache.join("abc-checksums.zip").exists());
+
+        let err = store.checksums("def").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(!cache.join("def-checksums.zip").exists());
+
+        let local = open(served.to_str().unwrap(), &cache);
+        assert_eq!(local.checksums("abc").unwrap(), "123 a.txt\n");
+
+        // A zip without the checksum list is missing it, a broken one is not.
+        let zip = served.join("ghi-checksums.zip");
+        zip_content("other", b"", zip.as_path().into()).unwrap();
+        let err = read_checksum_zip(&zip).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        std::fs::write(&zip, "broken").unwrap();
+        let err = read_checksum_zip(&zip).unwrap_err();
+        assert_ne!(err.kind(), ErrorKind::NotFound);
+        let err = read_checksum_zip(&served.join("jkl-checksums.zip")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
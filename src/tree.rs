@@ -0,0 +1,181 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use walkdir::WalkDir;
+
+use crate::exclude::Exclude;
+
+/// A directory mounted at `prefix` of the virtual build tree.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Mount {
+    pub dir: PathBuf,
+    pub prefix: String,
+}
+
+impl Mount {
+    pub fn new<P: Into<PathBuf>>(dir: P, prefix: &str) -> Self {
+        Self {
+            dir: dir.into(),
+            prefix: prefix.trim_matches('/').to_string(),
+        }
+    }
+
+    /// Parse `<dir>:<prefix>` or just `<dir>` for the top of the tree.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (dir, prefix) = s.rsplit_once(':').unwrap_or((s, ""));
+        if dir.is_empty() {
+            return Err(format!("missing directory in mount {s}"));
+        }
+        Ok(Self::new(dir, prefix))
+    }
+
+    fn name(&self, rel: &Path) -> String {
+        let rel = rel.to_string_lossy();
+        match (self.prefix.is_empty(), rel.is_empty()) {
+            (true, _) => rel.to_string(),
+            (false, true) => self.prefix.clone(),
+            (false, false) => format!("{}/{}", self.prefix, rel),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Entry {
+    /// Path in the virtual tree.
+    pub name: String,
+    /// Path on disk.
+    pub path: PathBuf,
+    pub is_dir: bool,
+}
+
+/// One or more directories hashed and zipped as one tree.
+#[derive(Clone, Debug)]
+pub(crate) struct Tree {
+    mounts: Vec<Mount>,
+}
+
+impl Tree {
+    pub fn new(mounts: Vec<Mount>) -> Self {
+        Self { mounts }
+    }
+
+    /// The directory mounted at the top of the tree, if any.
+    pub fn root(&self) -> Option<&Path> {
+        self.mounts
+            .iter()
+            .find(|m| m.prefix.is_empty())
+            .map(|m| m.dir.as_path())
+    }
+
+    /// All files and directories of all mounts, failing if two mounts
+    /// provide the same file.
+    pub fn walk(&self, exclude: &Exclude) -> std::io::Result<Vec<Entry>> {
+        let mut entries = vec![];
+        let mut seen: HashMap<String, (bool, PathBuf)> = HashMap::new();
+        let mut collisions = vec![];
+        for mount in &self.mounts {
+            for entry in WalkDir::new(&mount.dir)
+                .into_iter()
+                .filter_entry(|e| {
+                    let rel = e.path().strip_prefix(&mount.dir).unwrap_or(e.path());
+                    !exclude.is_excluded(mount.name(rel), e.file_type().is_dir())
+                })
+                .filter_map(|e| e.ok())
+            {
+                let path = entry.path();
+                let name = mount.name(path.strip_prefix(&mount.dir).unwrap());
+                if name.is_empty() {
+                    continue;
+                }
+                let is_dir = path.is_dir();
+                match seen.get(&name) {
+                    Some((true, _)) if is_dir => continue,
+                    Some((_, other)) => {
+                        collisions.push(format!(
+                            "{name}: {} and {}",
+                            other.display(),
+                            path.display()
+                        ));
+                        continue;
+                    }
+                    None => {}
+                }
+                seen.insert(name.clone(), (is_dir, path.to_path_buf()));
+                entries.push(Entry {
+                    name,
+                    path: path.to_path_buf(),
+                    is_dir,
+                });
+            }
+        }
+        if !collisions.is_empty() {
+            return Err(std::io::Error::other(format!(
+                "mounted roots collide:\n{}",
+                collisions.join("\n")
+            )));
+        }
+        Ok(entries)
+    }
+
+    /// Path on disk of `name` in the virtual tree.
+    pub fn resolve(&self, name: &str) -> Option<PathBuf> {
+        self.mounts.iter().find_map(|mount| {
+            let rel = if mount.prefix.is_empty() {
+                name
+            } else if name == mount.prefix {
+                ""
+            } else {
+                name.strip_prefix(&mount.prefix)?.strip_prefix('/')?
+            };
+            let path = mount.dir.join(rel);
+            path.exists().then_some(path)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_parse_mount() {
+        assert_eq!(Mount::parse("build"), Ok(Mount::new("build", "")));
+        assert_eq!(
+            Mount::parse("docs:/examples/"),
+            Ok(Mount::new("docs", "examples"))
+        );
+        assert!(Mount::parse(":bcd").is_err());
+    }
+
+    #[test]
+    fn test_walk_and_resolve() {
+        let dir = TempDir::new("tree");
+        let build = dir.join("build");
+        let docs = dir.join("docs");
+        std::fs::create_dir_all(build.join("examples")).unwrap();
+        std::fs::create_dir_all(&docs).unwrap();
+        std::fs::write(build.join("index.html"), "a").unwrap();
+        std::fs::write(docs.join("index.html"), "b").unwrap();
+
+        let tree = Tree::new(vec![Mount::new(&build, ""), Mount::new(&docs, "examples")]);
+        let mut names: Vec<_> = tree
+            .walk(&Exclude::default())
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["examples", "examples/index.html", "index.html"]);
+        assert_eq!(
+            tree.resolve("examples/index.html"),
+            Some(docs.join("index.html"))
+        );
+
+        std::fs::write(build.join("examples").join("index.html"), "c").unwrap();
+        let err = tree.walk(&Exclude::default()).unwrap_err();
+        assert!(err.to_string().contains("examples/index.html"));
+    }
+}
@@ -1,13 +1,183 @@
-use std::{fs::File, io::BufReader, path::Path};
+use std::{collections::BTreeMap, fs::File, io::BufReader, path::Path};
 
-use chrono::NaiveDateTime;
+use chrono::{DateTime, FixedOffset, NaiveDateTime, Utc};
+use serde::{Deserialize as _, Deserializer};
 use serde_derive::{Deserialize, Serialize};
 
+use crate::split::BundleDef;
+
+/// Version of the `update.json` format written by this version of differy.
+pub(crate) const UPDATE_FORMAT_VERSION: u32 = 3;
+
+/// Since this version every part of every zip has a `.differy/manifest.json`.
+pub(crate) const ZIP_MANIFEST_REQUIRED_SINCE: u32 = 3;
+
+/// The channel described by the top level of `update.json`, which is what
+/// clients without channel support read.
+pub(crate) const DEFAULT_CHANNEL: &str = "stable";
+
+/// A file to download, e.g. a zip. Zips split via `--max-part-size` are
+/// described by their part index and list their `parts`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub(crate) struct Artifact {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<Artifact>,
+    /// Signatures of `<name> <sha256>`, see `--signing-key`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<Signature>,
+}
+
+/// An Ed25519 signature by the key `key_id`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub(crate) struct Signature {
+    pub key_id: String,
+    pub signature: String,
+}
+
+/// Artifacts by variant (`web` or `app`).
+pub(crate) type Variants = BTreeMap<String, Artifact>;
+
+/// The full content zips of `latest`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub(crate) struct ContentInfo {
+    pub files: usize,
+    pub variants: Variants,
+    /// Content zips of locales and components by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub bundles: BTreeMap<String, Variants>,
+}
+
+/// The update zips from an old rev to `latest`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub(crate) struct UpdateInfo {
+    pub added: usize,
+    pub modified: usize,
+    pub removed: usize,
+    pub variants: Variants,
+    /// Update zips of locales and components by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub bundles: BTreeMap<String, Variants>,
+}
+
+/// Details about a rev listed in `latest` or `updates`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub(crate) struct RevInfo {
+    /// When the rev was packaged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<DateTime<Utc>>,
+    /// Commit date of the rev in mdn/content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit_date: Option<DateTime<FixedOffset>>,
+}
+
+impl RevInfo {
+    /// When the rev was packaged, or committed for revs packaged before
+    /// packaging dates were recorded.
+    pub fn date(&self) -> Option<DateTime<Utc>> {
+        self.date
+            .or_else(|| self.commit_date.map(|date| date.with_timezone(&Utc)))
+    }
+}
+
+/// An update zip from `from` to `to`, i.e. an edge of the update graph.
 #[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub(crate) struct Edge {
+    pub from: String,
+    pub to: String,
+    pub variants: Variants,
+}
+
+/// The cheapest chain of update zips from a rev to `latest`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub(crate) struct Plan {
+    /// The revs to update to in order, ending with `latest`.
+    pub revs: Vec<String>,
+    /// Total size of the web update zips.
+    pub size: u64,
+}
+
+fn format_v1() -> u32 {
+    1
+}
+
+/// Read RFC 3339 dates as well as the naive UTC dates of older files.
+fn deserialize_date<'de, D: Deserializer<'de>>(d: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+    let Some(date) = Option::<String>::deserialize(d)? else {
+        return Ok(None);
+    };
+    if let Ok(date) = DateTime::parse_from_rfc3339(&date) {
+        return Ok(Some(date.with_timezone(&Utc)));
+    }
+    date.parse::<NaiveDateTime>()
+        .map(|date| Some(date.and_utc()))
+        .map_err(serde::de::Error::custom)
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub(crate) struct Update {
-    pub date: Option<NaiveDateTime>,
+    /// Missing in version 1, which only had `date`, `latest` and `updates`.
+    #[serde(default = "format_v1")]
+    pub version: u32,
+    #[serde(default, deserialize_with = "deserialize_date")]
+    pub date: Option<DateTime<Utc>>,
     pub latest: Option<String>,
     pub updates: Vec<String>,
+    /// Locales with their own content and update zips, see `--locales`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub locales: Vec<String>,
+    /// Components with their own content and update zips, see `--components`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub components: Vec<BundleDef>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<ContentInfo>,
+    /// Details of the update zips for every rev in `updates`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub details: BTreeMap<String, UpdateInfo>,
+    /// Details of `latest` and every rev in `updates`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub revs: BTreeMap<String, RevInfo>,
+    /// Withdrawn revs, only listed in `updates` to downgrade clients on them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub yanked: Vec<String>,
+    /// Revs whose update zips would have been too large, see
+    /// `--max-update-ratio`. Clients on them download the content zips.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub full_download: Vec<String>,
+    /// All update zips still leading to `latest`, including older ones of
+    /// earlier latest revs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub graph: Vec<Edge>,
+    /// The cheapest chain through `graph` for every rev by size of the web
+    /// update zips, see `differy plan`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub plans: BTreeMap<String, Plan>,
+    /// Documents of all channels but the default one, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub channels: BTreeMap<String, Update>,
+}
+
+impl Default for Update {
+    fn default() -> Self {
+        Self {
+            version: UPDATE_FORMAT_VERSION,
+            date: None,
+            latest: None,
+            updates: vec![],
+            locales: vec![],
+            components: vec![],
+            content: None,
+            details: BTreeMap::new(),
+            revs: BTreeMap::new(),
+            yanked: vec![],
+            full_download: vec![],
+            graph: vec![],
+            plans: BTreeMap::new(),
+            channels: BTreeMap::new(),
+        }
+    }
 }
 
 impl Update {
@@ -20,9 +190,129 @@ impl Update {
         Ok(u)
     }
 
+    /// The document of `channel`.
+    pub fn channel(&self, channel: &str) -> Option<Update> {
+        if channel == DEFAULT_CHANNEL {
+            Some(Update {
+                channels: BTreeMap::new(),
+                ..self.clone()
+            })
+        } else {
+            self.channels.get(channel).cloned()
+        }
+    }
+
+    /// The document of the channel `rev` is the latest of, trying `channel`
+    /// first.
+    pub fn channel_with_latest(&self, channel: &str, rev: &str) -> Option<Update> {
+        self.channel(channel)
+            .into_iter()
+            .chain(self.channel(DEFAULT_CHANNEL))
+            .chain(self.channels.values().cloned())
+            .find(|update| update.latest.as_deref() == Some(rev))
+    }
+
+    /// Whether clients on `rev` can get to `latest`, by update zips or a
+    /// full download.
+    pub fn reaches(&self, rev: &str) -> bool {
+        self.latest.as_deref() == Some(rev)
+            || self.updates.iter().any(|old| old == rev)
+            || self.plans.contains_key(rev)
+            || self.full_download.iter().any(|old| old == rev)
+    }
+
+    /// Replace the document of `channel`, keeping all other channels.
+    pub fn set_channel(&mut self, channel: &str, update: Update) {
+        if channel == DEFAULT_CHANNEL {
+            let channels = std::mem::take(&mut self.channels);
+            *self = Update { channels, ..update };
+        } else {
+            self.channels.insert(channel.to_string(), update);
+        }
+    }
+
+    /// Replace `path` atomically, so clients never see a truncated file.
     pub fn save(&self, path: &Path) -> std::io::Result<()> {
-        let file = File::create(path)?;
-        serde_json::to_writer_pretty(file, self)?;
-        Ok(())
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = path.with_file_name(format!(".{file_name}.tmp"));
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.sync_all()?;
+        std::fs::rename(tmp, path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+
+    use super::{Update, DEFAULT_CHANNEL};
+
+    #[test]
+    fn test_read_v1() {
+        let v1 = r#"{
+            "date": "2021-08-20T13:43:20.024561",
+            "latest": "3bfe5e8ee",
+            "updates": ["723965504"]
+        }"#;
+        let update: Update = serde_json::from_str(v1).unwrap();
+        assert_eq!(update.version, 1);
+        let date = Utc.with_ymd_and_hms(2021, 8, 20, 13, 43, 20).unwrap()
+            + chrono::Duration::microseconds(24561);
+        assert_eq!(update.date, Some(date));
+        assert_eq!(update.latest.as_deref(), Some("3bfe5e8ee"));
+        assert_eq!(update.updates, ["723965504"]);
+        assert!(update.content.is_none());
+        assert!(update.details.is_empty());
+    }
+
+    #[test]
+    fn test_dates() {
+        let update: Update =
+            serde_json::from_str(r#"{"date": "2021-08-20T15:43:20+02:00", "updates": []}"#)
+                .unwrap();
+        let date = Utc.with_ymd_and_hms(2021, 8, 20, 13, 43, 20).unwrap();
+        assert_eq!(update.date, Some(date));
+        let json = serde_json::to_value(&update).unwrap();
+        assert_eq!(json["date"], "2021-08-20T13:43:20Z");
+        let update: Update = serde_json::from_value(json).unwrap();
+        assert_eq!(update.date, Some(date));
+    }
+
+    #[test]
+    fn test_channels() {
+        let mut update = Update {
+            latest: Some("a".into()),
+            ..Default::default()
+        };
+        let beta = Update {
+            latest: Some("b".into()),
+            updates: vec!["a".into()],
+            ..Default::default()
+        };
+        update.set_channel("beta", beta);
+        assert_eq!(update.latest.as_deref(), Some("a"));
+        assert_eq!(update.channel("beta").unwrap().latest.as_deref(), Some("b"));
+        assert!(update.channel("nightly").is_none());
+
+        let promoted = update.channel("beta").unwrap();
+        assert!(promoted.reaches("a"));
+        assert!(!promoted.reaches("c"));
+        update.set_channel(DEFAULT_CHANNEL, promoted);
+        assert_eq!(update.latest.as_deref(), Some("b"));
+        assert_eq!(update.updates, ["a"]);
+        assert!(update.channels.contains_key("beta"));
+        let stable = update.channel(DEFAULT_CHANNEL).unwrap();
+        assert!(stable.channels.is_empty());
+
+        let nightly = Update {
+            latest: Some("c".into()),
+            ..Default::default()
+        };
+        update.set_channel("nightly", nightly);
+        let found = update.channel_with_latest(DEFAULT_CHANNEL, "c").unwrap();
+        assert!(found.channels.is_empty());
+        assert_eq!(found.latest.as_deref(), Some("c"));
+        assert!(update.channel_with_latest("nightly", "a").is_none());
     }
 }